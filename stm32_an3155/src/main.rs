@@ -1,20 +1,64 @@
+mod progress;
+
 use anyhow::Context;
 use clap::Parser;
 #[allow(unused_imports)]
 use log::{debug, info, trace, warn};
-use std::{cmp::Ordering, fs, time::Duration};
-use stm32_an3155_rs::{Builder, DEFAULT_BAUDRATE};
+use progress::ProgressBar;
+use std::{
+    fmt, fs,
+    io::{ErrorKind, Write},
+    net::TcpStream,
+    num::ParseIntError,
+    path::Path,
+    process::ExitCode,
+    str::FromStr,
+    time::Duration,
+};
+use stm32_an3155_rs::{
+    BankErase, BaudProbe, BootPins, Builder, ControlLine, Device, EraseCommand, Error, FlashLayout,
+    FlashOptions, Image, RetryPolicy, Transport, AN3155, DEFAULT_BAUDRATE, DEFAULT_BOOT_DELAY,
+    DEFAULT_RESET_PULSE, DEFAULT_START_ADDRESS, DEFAULT_SYNC_ATTEMPTS, DEFAULT_SYNC_DELAY,
+    ELF_MAGIC, MIN_SYNC_ATTEMPTS,
+};
+
+/// Invalid input caught by the command line tool rather than the library,
+/// reported with [`Exit::InvalidInput`]
+#[derive(Debug)]
+struct InvalidInput(String);
+
+impl fmt::Display for InvalidInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidInput {}
+
+/// Return early with an [`InvalidInput`] error, like [`anyhow::bail!`]
+macro_rules! invalid_input {
+    ($($arg:tt)*) => {
+        return Err(InvalidInput(format!($($arg)*)).into())
+    };
+}
 
 #[derive(clap::Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, after_help = EXIT_CODES_HELP)]
 struct Opt {
-    /// Serial port
+    /// Serial port, or tcp://HOST:PORT for a networked serial bridge
     #[arg(short, long, default_value_t = String::from("/dev/ttyUSB0"))]
     port: String,
 
-    /// Baud rate
-    #[arg(short, long, default_value_t = DEFAULT_BAUDRATE)]
-    baud_rate: u32,
+    /// Baud rate, or auto to use the fastest rate that passes a link check.  Auto needs
+    /// --boot-pins, as the bootloader must be reset to try another rate
+    #[arg(
+        short,
+        long,
+        visible_alias = "baud",
+        default_value_t = Baud::Rate(DEFAULT_BAUDRATE),
+        requires_if("auto", "boot_pins")
+    )]
+    baud_rate: Baud,
 
     /// Skip baud rate initialization
     #[arg(short, long)]
@@ -24,6 +68,51 @@ struct Opt {
     #[arg(short, long, default_value_t = 1_000u64)]
     timeout_ms: u64,
 
+    /// Number of sync bytes to send before giving up on the bootloader, at least 2
+    #[arg(
+        long,
+        default_value_t = DEFAULT_SYNC_ATTEMPTS,
+        value_parser = clap::value_parser!(u32).range(MIN_SYNC_ATTEMPTS as i64..)
+    )]
+    sync_attempts: u32,
+
+    /// Number of times to retry reads, writes and queries that fail with a NACK or timeout
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+
+    /// Flash page size, in bytes.  Overrides the layout detected from the product ID
+    #[arg(long, requires = "flash_size", value_parser = clap::value_parser!(u32).range(1..))]
+    page_size: Option<u32>,
+
+    /// Flash memory size, in KiB.  Overrides the layout detected from the product ID
+    #[arg(long, requires = "page_size", value_parser = clap::value_parser!(u32).range(1..))]
+    flash_size: Option<u32>,
+
+    /// Reset into the bootloader before connecting, and into the application when done,
+    /// with DTR wired to NRST and RTS wired to BOOT0
+    #[arg(long)]
+    boot_pins: bool,
+
+    /// Swap the control lines: RTS drives NRST and DTR drives BOOT0
+    #[arg(long, requires = "boot_pins")]
+    swap_boot_pins: bool,
+
+    /// Deassert the reset line to hold the chip in reset
+    #[arg(long, requires = "boot_pins")]
+    invert_reset: bool,
+
+    /// Deassert the BOOT0 line to select the bootloader
+    #[arg(long, requires = "boot_pins")]
+    invert_boot0: bool,
+
+    /// Time the reset line is held active, in milliseconds
+    #[arg(long, requires = "boot_pins", default_value_t = DEFAULT_RESET_PULSE.as_millis() as u64)]
+    reset_pulse_ms: u64,
+
+    /// Time to wait for the chip to boot after reset, in milliseconds
+    #[arg(long, requires = "boot_pins", default_value_t = DEFAULT_BOOT_DELAY.as_millis() as u64)]
+    boot_delay_ms: u64,
+
     #[command(subcommand)]
     command: Option<Command>,
 }
@@ -34,26 +123,466 @@ enum Command {
     Info,
     /// Flash new firmware from given file
     Flash {
-        /// Filename of raw firmware binary
+        /// Filename of firmware image
         file: String,
 
-        /// Starting address to write firmware to
+        /// Starting address to write a raw firmware binary to
         #[arg(short, long, default_value_t = String::from("0x08000000"))]
         address: String,
 
+        /// Firmware image format
+        #[arg(short, long, value_enum, default_value_t = ImageFormat::Auto)]
+        format: ImageFormat,
+
         /// Don't verify bytes written after flashing.
         #[arg(short, long)]
         skip_verification: bool,
+
+        /// How to verify bytes written after flashing
+        #[arg(long, value_enum, default_value_t = VerifyMode::Auto)]
+        verify: VerifyMode,
+
+        /// Start the firmware after flashing, from the vector table of the image
+        #[arg(long)]
+        run: bool,
+    },
+    /// Compare a firmware image with device memory without writing
+    Verify {
+        /// Filename of firmware image
+        file: String,
+
+        /// Starting address of a raw firmware binary
+        #[arg(short, long, default_value_t = String::from("0x08000000"))]
+        address: String,
+
+        /// Firmware image format
+        #[arg(short, long, value_enum, default_value_t = ImageFormat::Auto)]
+        format: ImageFormat,
+    },
+    /// Erase flash pages, an address range, a bank or all flash memory
+    #[command(group(clap::ArgGroup::new("target").required(true).args(["pages", "address", "bank", "mass"])))]
+    Erase {
+        /// Page or sector numbers to erase
+        pages: Vec<u32>,
+
+        /// Erase every page touched by the address range starting here
+        #[arg(short, long, requires = "length")]
+        address: Option<String>,
+
+        /// Length of the address range, in decimal or 0x-prefixed hex
+        #[arg(short, long, requires = "address")]
+        length: Option<String>,
+
+        /// Erase one flash bank with a bank erase
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=2))]
+        bank: Option<u8>,
+
+        /// Erase all flash memory with a global erase
+        #[arg(long)]
+        mass: bool,
+    },
+    /// Read memory to a file, or to stdout as a hexdump
+    Read {
+        /// Starting address to read from
+        #[arg(short, long, default_value_t = String::from("0x08000000"))]
+        address: String,
+
+        /// Number of bytes to read, in decimal or 0x-prefixed hex
+        #[arg(short, long)]
+        length: String,
+
+        /// Output file.  A hexdump is printed to stdout if not given
+        output: Option<String>,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = DumpFormat::Auto)]
+        format: DumpFormat,
     },
+    /// Jump to application code
+    Go {
+        /// Address of the application vector table
+        #[arg(short, long, default_value_t = String::from("0x08000000"))]
+        address: String,
+    },
+    /// Enable write protection for flash sectors
+    WriteProtect {
+        /// Sector numbers to protect
+        #[arg(required = true)]
+        sectors: Vec<u8>,
+    },
+    /// Send a raw Special or ExtendedSpecial command
+    Special {
+        /// Command opcode
+        #[arg(short, long)]
+        opcode: String,
+
+        /// Data bytes, as a hex string
+        #[arg(short, long, default_value_t = String::new())]
+        data: String,
+
+        /// Send an ExtendedSpecial command
+        #[arg(short, long)]
+        extended: bool,
+
+        /// File data bytes for an ExtendedSpecial command, as a hex string
+        #[arg(short, long, requires = "extended")]
+        file_data: Option<String>,
+    },
+    /// Enable readout protection
+    Protect,
+    /// Disable readout protection.  This mass erases the flash memory
+    Unprotect {
+        /// Confirm that the flash memory will be erased
+        #[arg(long)]
+        confirm_mass_erase: bool,
+    },
+    /// Show or change the option bytes
+    OptionBytes {
+        #[command(subcommand)]
+        action: OptionBytesAction,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum OptionBytesAction {
+    /// Print every option byte field with its value
+    Show,
+    /// Change option byte fields and write them back.  This resets the device
+    Set {
+        /// Fields to change, as NAME=VALUE with the value in decimal or 0x-prefixed hex
+        #[arg(required = true)]
+        fields: Vec<String>,
+    },
+}
+
+/// Baud rate given on the command line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Baud {
+    /// Probe for the fastest working rate
+    Auto,
+    Rate(u32),
+}
+
+impl FromStr for Baud {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Baud::Auto),
+            rate => rate.parse().map(Baud::Rate),
+        }
+    }
+}
+
+impl fmt::Display for Baud {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Baud::Auto => write!(f, "auto"),
+            Baud::Rate(rate) => write!(f, "{rate}"),
+        }
+    }
+}
+
+/// Method used to verify flashed data
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum VerifyMode {
+    /// Use on-chip CRC if the bootloader supports it, otherwise read back
+    Auto,
+    /// Compare the CRC computed by the bootloader
+    Crc,
+    /// Read back every written chunk
+    ReadBack,
+}
+
+/// Firmware image file format
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum ImageFormat {
+    /// Detect the format from the file contents or extension
+    Auto,
+    /// Raw binary written at --address
+    Binary,
+    /// Intel HEX
+    Ihex,
+    /// ELF executable
+    Elf,
+    /// Motorola S-record
+    Srec,
+}
+
+/// Memory dump output format
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum DumpFormat {
+    /// Intel HEX for .hex files, raw binary for other files, hexdump on stdout
+    Auto,
+    /// Raw binary
+    Binary,
+    /// Intel HEX
+    Ihex,
+    /// Hexdump with ASCII column
+    Hexdump,
+}
+
+/// Load a firmware image, detecting its format from the file contents or extension if needed
+fn load_image(file: &str, format: ImageFormat, address: u32) -> anyhow::Result<Image> {
+    let bytes = fs::read(file).with_context(|| format! {"Failed to read {file}"})?;
+    let format = match format {
+        ImageFormat::Auto if bytes.starts_with(ELF_MAGIC) => ImageFormat::Elf,
+        ImageFormat::Auto => {
+            let extension = Path::new(file)
+                .extension()
+                .and_then(|e| e.to_str())
+                .map(|e| e.to_ascii_lowercase());
+            match extension.as_deref() {
+                Some("hex" | "ihex" | "ihx") => ImageFormat::Ihex,
+                Some("srec" | "s19" | "s28" | "s37" | "mot") => ImageFormat::Srec,
+                _ => ImageFormat::Binary,
+            }
+        }
+        format => format,
+    };
+    debug! {"loading {file} as {format:?}"};
+
+    let image = match format {
+        ImageFormat::Elf => Image::from_elf(&bytes)?,
+        ImageFormat::Ihex => {
+            let text = String::from_utf8(bytes)
+                .with_context(|| format! {"{file} is not a valid Intel HEX file"})?;
+            Image::from_ihex(&text)?
+        }
+        ImageFormat::Srec => {
+            let text = String::from_utf8(bytes)
+                .with_context(|| format! {"{file} is not a valid S-record file"})?;
+            Image::from_srec(&text)?
+        }
+        _ => Image::from_binary(address, bytes),
+    };
+    Ok(image)
+}
+
+impl Opt {
+    /// Wiring of the reset and BOOT0 pins, if the control lines drive them
+    fn boot_pins(&self) -> Option<BootPins> {
+        if !self.boot_pins {
+            return None;
+        }
+        let lines = match self.swap_boot_pins {
+            true => (ControlLine::Rts, ControlLine::Dtr),
+            false => (ControlLine::Dtr, ControlLine::Rts),
+        };
+        let pins = BootPins::default()
+            .and_lines(lines.0, lines.1)
+            .and_inverted(self.invert_reset, self.invert_boot0)
+            .and_timing(
+                Duration::from_millis(self.reset_pulse_ms),
+                Duration::from_millis(self.boot_delay_ms),
+            );
+        Some(pins)
+    }
+
+    /// Flash memory layout from the command line options or the detected device
+    fn flash_layout<T: Transport>(&self, an3155: &mut AN3155<T>) -> anyhow::Result<FlashLayout> {
+        if let (Some(page_size), Some(flash_size)) = (self.page_size, self.flash_size) {
+            let Some(flash_bytes) = flash_size.checked_mul(1024) else {
+                invalid_input! {"Flash size of {flash_size} KiB does not fit in the address space"};
+            };
+            let page_count = flash_bytes / page_size;
+            debug! {"using flash layout from options: {page_count} pages of {page_size} bytes"};
+            return Ok(FlashLayout::uniform(
+                DEFAULT_START_ADDRESS,
+                page_size,
+                page_count,
+            ));
+        }
+
+        an3155
+            .get_flash_layout()
+            .context("Pass --page-size and --flash-size to describe the device flash")
+    }
+}
+
+/// Parse a string of hex digit pairs, e.g. "0A1B2C", into bytes
+fn parse_bytes(hex: &str) -> anyhow::Result<Vec<u8>> {
+    let hex = hex.trim_start_matches("0x");
+    if !hex.len().is_multiple_of(2) {
+        invalid_input! {"Hex string must have an even number of digits: {hex}"};
+    }
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        invalid_input! {"Unable to parse bytes from string, expected hex digits: {hex}"};
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
+
+/// Format bytes as a string of hex digit pairs
+fn format_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format! {"{b:02X}"}).collect()
+}
+
+/// Number of bytes shown for each mismatched region in a verification report
+const REPORT_BYTES: usize = 16;
+
+/// Format bytes as space separated hex, truncated to REPORT_BYTES
+fn format_report_bytes(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes
+        .iter()
+        .take(REPORT_BYTES)
+        .map(|b| format! {"{b:02X}"})
+        .collect();
+    match bytes.len() > REPORT_BYTES {
+        true => format! {"{} ...", hex.join(" ")},
+        false => hex.join(" "),
+    }
+}
+
+/// Format bytes as lines of 16 hex bytes followed by their ASCII characters
+fn hexdump(address: u32, bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for (index, line) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format! {"{b:02X}"}).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&b| match b.is_ascii_graphic() || b == b' ' {
+                true => b as char,
+                false => '.',
+            })
+            .collect();
+        let line_address = address.wrapping_add(index as u32 * 16);
+        text.push_str(&format! {"{line_address:08X}  {:<47}  |{ascii}|\n", hex.join(" ")});
+    }
+    text
+}
+
+/// Parse a number in decimal, or in hexadecimal with a 0x prefix
+fn parse_number(number: &str, what: &str) -> anyhow::Result<u32> {
+    match number.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => number.parse(),
+    }
+    .with_context(|| format! {"Unable to parse {what} from string: {number}"})
+}
+
+/// Parse a hexadecimal address with an optional 0x prefix
+fn parse_address(address: &str) -> anyhow::Result<u32> {
+    u32::from_str_radix(address.trim_start_matches("0x"), 16)
+        .with_context(|| format! {"Unable to parse address from string: {address}"})
+}
+
+/// Process exit codes
+///
+/// These are listed in the `--help` output, so scripts can tell a device
+/// that is not responding from an image that failed verification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Exit {
+    /// Any other error
+    Failure = 1,
+    // 2 is used by clap for invalid command line arguments
+    /// Device memory does not match the image
+    VerifyFailed = 3,
+    /// Invalid image file, address, page number or other input
+    InvalidInput = 4,
+    /// No response, timeout, NACK or invalid response from the bootloader
+    Communication = 5,
+}
+
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  Success
+  1  Other error
+  2  Invalid command line arguments
+  3  Verification failed, device memory does not match the image
+  4  Invalid input, e.g. image file, address or page number
+  5  Communication with the bootloader failed";
+
+impl Exit {
+    fn from_io(error: &std::io::Error) -> Self {
+        match error.kind() {
+            ErrorKind::NotFound | ErrorKind::PermissionDenied | ErrorKind::IsADirectory => {
+                Exit::InvalidInput
+            }
+            _ => Exit::Communication,
+        }
+    }
+}
+
+impl From<&anyhow::Error> for Exit {
+    /// Classify an error by the first cause in its chain with a known type
+    fn from(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(error) = cause.downcast_ref::<Error>() {
+                return match error.root() {
+                    Error::VerifyMismatch { .. } => Exit::VerifyFailed,
+                    Error::Io(error) => Exit::from_io(error),
+                    Error::Serial(_)
+                    | Error::NoBootloader { .. }
+                    | Error::NoBaudRate { .. }
+                    | Error::InvalidResponse(_)
+                    | Error::Nack(_)
+                    | Error::InvalidBootloaderCommand(_)
+                    | Error::ResponseChecksum
+                    | Error::ResponseLength { .. } => Exit::Communication,
+                    Error::Unsupported(_) => Exit::Failure,
+                    _ => Exit::InvalidInput,
+                };
+            }
+            if let Some(error) = cause.downcast_ref::<std::io::Error>() {
+                return Exit::from_io(error);
+            }
+            if cause.is::<InvalidInput>()
+                || cause.is::<std::num::ParseIntError>()
+                || cause.is::<std::string::FromUtf8Error>()
+            {
+                return Exit::InvalidInput;
+            }
+        }
+        Exit::Failure
+    }
 }
 
-fn main() -> anyhow::Result<()> {
+fn main() -> ExitCode {
     env_logger::init();
     let cli = Opt::parse();
 
-    let builder = Builder::with_path(&cli.port)
-        .and_baud_rate(cli.baud_rate)
-        .and_timeout(Duration::from_millis(cli.timeout_ms));
+    match connect(&cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln! {"Error: {error:?}"};
+            ExitCode::from(Exit::from(&error) as u8)
+        }
+    }
+}
+
+/// Open the port given on the command line and run the command
+fn connect(cli: &Opt) -> anyhow::Result<()> {
+    match cli.port.strip_prefix("tcp://") {
+        Some(address) => {
+            info! {"connecting to serial bridge at {address}"};
+            let stream = TcpStream::connect(address)
+                .with_context(|| format! {"Failed to connect to {address}"})?;
+            let builder = Builder::with_transport(stream);
+            run(cli, builder)
+        }
+        None => {
+            let builder = Builder::with_path(&cli.port);
+            run(cli, builder)
+        }
+    }
+}
+
+fn run<T: Transport>(cli: &Opt, builder: Builder<T>) -> anyhow::Result<()> {
+    let builder = match cli.baud_rate {
+        Baud::Auto => builder.and_baud_probe(BaudProbe::default()),
+        Baud::Rate(rate) => builder.and_baud_rate(rate),
+    };
+    let builder = builder
+        .and_timeout(Duration::from_millis(cli.timeout_ms))
+        .and_sync_attempts(cli.sync_attempts, DEFAULT_SYNC_DELAY)
+        .and_retry_policy(RetryPolicy::new(cli.retries.saturating_add(1)));
+    let builder = match cli.boot_pins() {
+        Some(pins) => builder.and_boot_pins(pins),
+        None => builder,
+    };
 
     let mut an3155 = match cli.skip_initialization {
         true => builder.skip_initialization(),
@@ -61,123 +590,251 @@ fn main() -> anyhow::Result<()> {
     }
     .context("Failed to create bootloader comms object")?;
 
-    match cli.command.unwrap_or(Command::Info) {
+    let command = cli.command.as_ref().unwrap_or(&Command::Info);
+    // The application is already running if it was started with a Go command
+    let started = matches!(
+        command,
+        Command::Go { .. } | Command::Flash { run: true, .. }
+    );
+
+    match command {
         Command::Info => {
-            let version = an3155.get_version()?;
-            let (major, minor) = version.value();
-            let commands = an3155.get_commands()?;
+            let info = an3155.get_info()?.clone();
+            let (major, minor) = info.version.value();
             let product_id = an3155.get_id()?;
             println! {"Product ID: 0x{:04X?}", product_id}
-            println! {"Bootloader version: {major}.{minor}"}
-            print! {"Available commands: " }
-            for command in &commands[..commands.len() - 1] {
-                print! {"{:?}, ", command};
+            if let Some(device) = Device::from_pid(product_id) {
+                println! {"Device: {}", device.name};
+                println! {"Flash: {} KiB at 0x{:08X}", device.flash.size() / 1024, device.flash.base()};
             }
-            println! {"{:?}", commands.last().unwrap()};
+            println! {"Bootloader version: {major}.{minor}"}
+            println! {"Option bytes: {}", format_report_bytes(&info.option_bytes)};
+            let mut commands: Vec<String> =
+                info.commands().iter().map(|c| format! {"{c:?}"}).collect();
+            commands.extend(info.unknown_opcodes().iter().map(|o| format! {"0x{o:02X}"}));
+            println! {"Available commands: {}", commands.join(", ")};
         }
         Command::Flash {
             address: address_str,
             file,
+            format,
             skip_verification,
+            verify,
+            run,
         } => {
-            let size = fs::metadata(&file)?.len();
-            let address = u32::from_str_radix(&address_str.trim_start_matches("0x"), 16)
-                .with_context(|| format! {"Unable to parse address from string: {address_str}"})?;
-            if address < stm32_an3155_rs::DEFAULT_START_ADDRESS {
-                panic! {"Invalid starting address: {address_str}"};
-            }
-            info! {"Flashing {file} ({size} bytes) to address: {address_str}"};
-
-            let pages_to_erase: Vec<u32> = {
-                let start_offset = address - stm32_an3155_rs::DEFAULT_START_ADDRESS;
-                let start_page = start_offset / (stm32_an3155_rs::DEFAULT_PAGE_SIZE as u32);
-                let num_pages =
-                    ((size as f64) / (stm32_an3155_rs::DEFAULT_PAGE_SIZE as f64)).ceil() as u32;
-                debug! {"starting page: {start_page}, num_pages: {num_pages}"};
-                (start_page..start_page + num_pages).collect()
-            };
+            let address = parse_address(address_str)?;
+            let image = load_image(file, *format, address)?;
+            info! {"Flashing {file} ({} bytes in {} segments)", image.size(), image.segments.len()};
 
-            //an3155.write_unprotect()?;
-            match an3155.get_erase_command()? {
-                stm32_an3155_rs::EraseCommand::Erase => {
-                    debug! {"using standard erase command"};
-                    if let Some(x) = pages_to_erase.iter().find(|&x| *x > u8::MAX.into()) {
-                        panic! {"Invalid page number: {}.  Max value is {}", x, u8::MAX};
-                    }
-                    // Convert pages into u8 values
-                    let pages_to_erase: Vec<u8> =
-                        pages_to_erase.into_iter().map(|x| x as u8).collect();
+            let verify = match (skip_verification, verify) {
+                (true, _) => stm32_an3155_rs::VerifyMode::Skip,
+                (false, VerifyMode::Auto) => stm32_an3155_rs::VerifyMode::Auto,
+                (false, VerifyMode::Crc) => stm32_an3155_rs::VerifyMode::Crc,
+                (false, VerifyMode::ReadBack) => stm32_an3155_rs::VerifyMode::ReadBack,
+            };
+            let options = FlashOptions::default()
+                .and_layout(cli.flash_layout(&mut an3155)?)
+                .and_verify(verify)
+                .and_go(*run);
+            an3155.flash_image(&image, &options, &mut ProgressBar::new())?;
+        }
+        Command::Verify {
+            file,
+            address,
+            format,
+        } => {
+            let address = parse_address(address)?;
+            let image = load_image(file, *format, address)?;
+            info! {"verifying {file} ({} bytes in {} segments)", image.size(), image.segments.len()};
 
-                    debug! {"pages to erase: {:?}", pages_to_erase};
+            let mismatches = an3155.verify_image(&image, &mut ProgressBar::new())?;
 
-                    // Erase pages
-                    for chunk in pages_to_erase.chunks(stm32_an3155_rs::MAX_ERASE_PAGE_COUNT) {
-                        an3155.standard_erase(chunk)?;
-                    }
+            if !mismatches.is_empty() {
+                for mismatch in &mismatches {
+                    println! {"Mismatch at 0x{:08X} ({} bytes)", mismatch.address, mismatch.expected.len()};
+                    println! {"  expected: {}", format_report_bytes(&mismatch.expected)};
+                    println! {"  actual:   {}", format_report_bytes(&mismatch.actual)};
                 }
-                stm32_an3155_rs::EraseCommand::ExtendedErase => {
-                    debug! {"using extended erase command"};
-                    if let Some(x) = pages_to_erase.iter().find(|&x| *x > u16::MAX.into()) {
-                        panic! {"Invalid page number: {}.  Max value is {}", x, u16::MAX};
+                let bytes: usize = mismatches.iter().map(|m| m.expected.len()).sum();
+                let address = mismatches[0].address;
+                return Err(Error::VerifyMismatch { address }).with_context(|| {
+                    format! {"{bytes} bytes differ in {} regions", mismatches.len()}
+                });
+            }
+            println! {"Verified {} bytes in {} segments", image.size(), image.segments.len()};
+        }
+        Command::Erase {
+            pages,
+            address,
+            length,
+            bank,
+            mass,
+        } => {
+            if *mass {
+                an3155.mass_erase()?;
+                println! {"Erased all flash memory"};
+            } else if let Some(bank) = bank {
+                if let Some(device) = an3155.get_device()? {
+                    if *bank > device.banks {
+                        invalid_input! {"{} has only {} flash bank(s)", device.name, device.banks};
                     }
-                    let pages_to_erase: Vec<u16> =
-                        pages_to_erase.into_iter().map(|x| x as u16).collect();
-                    debug! {"pages to erase: {:?}", pages_to_erase};
-                    an3155.extended_erase(&pages_to_erase)?;
                 }
+                if an3155.get_erase_command()? != EraseCommand::ExtendedErase {
+                    anyhow::bail! {"Bank erase requires the extended erase command, which the bootloader does not support"};
+                }
+                let target = match bank {
+                    1 => BankErase::Bank1,
+                    _ => BankErase::Bank2,
+                };
+                an3155.extended_global_erase(target)?;
+                println! {"Erased flash bank {bank}"};
+            } else {
+                let layout = cli.flash_layout(&mut an3155)?;
+                let pages = match (address, length) {
+                    (Some(address), Some(length)) => {
+                        let address = parse_address(address)?;
+                        let length = parse_number(length, "length")?;
+                        let pages = layout.sectors_in_range(address, length).ok_or(
+                            Error::AddressOutOfRange {
+                                address,
+                                len: length as usize,
+                            },
+                        )?;
+                        pages.map(|page| page as u32).collect()
+                    }
+                    _ => {
+                        let count = layout.sector_count() as u32;
+                        if let Some(&page) = pages.iter().find(|&&page| page >= count) {
+                            let max = count.saturating_sub(1);
+                            return Err(Error::PageOutOfRange { page, max }.into());
+                        }
+                        pages.clone()
+                    }
+                };
+                an3155.erase_pages(&pages, &mut ProgressBar::new())?;
+                println! {"Erased pages {pages:?}"};
             }
+        }
+        Command::Read {
+            address,
+            length,
+            output,
+            format,
+        } => {
+            let address = parse_address(address)?;
+            let length = parse_number(length, "length")?;
+            if address as u64 + length as u64 > 1 << 32 {
+                let len = length as usize;
+                return Err(Error::AddressOutOfRange { address, len }.into());
+            }
+            let mut bytes = vec![0u8; length as usize];
+            an3155
+                .read_region(address, &mut bytes, &mut ProgressBar::new())
+                .with_context(|| format! {"Failed to read memory at 0x{address:08X}"})?;
 
-            info! {"writing {size} bytes to memory"};
-            let bytes = fs::read(&file)?;
-            for (index, chunk) in bytes
-                .chunks(stm32_an3155_rs::MAX_WRITE_BYTES_COUNT)
-                .enumerate()
-            {
-                let addr = address + (index * stm32_an3155_rs::MAX_WRITE_BYTES_COUNT) as u32;
-                debug! {"writing chunk #{} to address: 0x{addr:08X}", index + 1}
-                an3155.write_memory(addr, chunk)?;
-                if !skip_verification {
-                    info! {"reading back memory for verification"};
-                    let mut buf = vec![0u8; chunk.len()];
-                    debug! {"reading chunk #{} from address: 0x{addr:08X}", index + 1}
-                    an3155.read_memory(addr, &mut buf)?;
-                    debug! {"comparing bytes with original file"};
-                    for (byte, (original, written)) in chunk.iter().zip(buf.iter()).enumerate() {
-                        match original.cmp(&written) {
-                            Ordering::Equal => continue,
-                            _ => {
-                                panic! {"Verification failed for byte #{}", byte}
-                            }
-                        }
+            let format = match (format, output) {
+                (DumpFormat::Auto, None) => DumpFormat::Hexdump,
+                (DumpFormat::Auto, Some(file)) => {
+                    let extension = Path::new(file)
+                        .extension()
+                        .and_then(|e| e.to_str())
+                        .map(|e| e.to_ascii_lowercase());
+                    match extension.as_deref() {
+                        Some("hex" | "ihex" | "ihx") => DumpFormat::Ihex,
+                        _ => DumpFormat::Binary,
                     }
                 }
+                (format, _) => *format,
+            };
+            let contents = match format {
+                DumpFormat::Ihex => Image::from_binary(address, bytes).to_ihex().into_bytes(),
+                DumpFormat::Hexdump => hexdump(address, &bytes).into_bytes(),
+                _ => bytes,
+            };
+            match output {
+                Some(file) => {
+                    fs::write(file, contents)
+                        .with_context(|| format! {"Failed to write {file}"})?;
+                    info! {"wrote {length} bytes from 0x{address:08X} to {file}"};
+                }
+                None => std::io::stdout().write_all(&contents)?,
             }
-
-            // if !skip_verification {
-            //     info! {"reading back memory for verification"};
-            //     let mut buf: Vec<u8> = Vec::with_capacity(size as usize);
-            //     buf.resize(size as usize, 0);
-            //     for (index, chunk) in buf
-            //         .chunks_mut(stm32_an3155_rs::MAX_READ_BYTES_COUNT)
-            //         .enumerate()
-            //     {
-            //         let addr = address + (index * stm32_an3155_rs::MAX_WRITE_BYTES_COUNT) as u32;
-            //         debug! {"reading chunk #{} from address: 0x{addr:08X}", index + 1}
-            //         an3155.read_memory(addr, chunk)?;
-            //     }
-
-            //     debug! {"comparing bytes with original file"};
-            //     for (byte, (original, written)) in bytes.iter().zip(buf.iter()).enumerate() {
-            //         match original.cmp(&written) {
-            //             Ordering::Equal => continue,
-            //             _ => {
-            //                 panic! {"Verification failed for byte #{}", byte}
-            //             }
-            //         }
-            //     }
-            // }
+        }
+        Command::Go { address } => {
+            let address = parse_address(address)?;
+            an3155.go(address)?;
+            println! {"Started application at 0x{address:08X}"};
+        }
+        Command::WriteProtect { sectors } => {
+            let layout = cli.flash_layout(&mut an3155)?;
+            an3155.write_protect(&layout, sectors)?;
+            println! {"Write protection enabled for sectors {sectors:?}"};
+        }
+        Command::Special {
+            opcode,
+            data,
+            extended,
+            file_data,
+        } => {
+            let opcode = u16::from_str_radix(opcode.trim_start_matches("0x"), 16)
+                .with_context(|| format! {"Unable to parse opcode from string: {opcode}"})?;
+            let data = parse_bytes(data)?;
+            if *extended {
+                let file_data = parse_bytes(file_data.as_deref().unwrap_or_default())?;
+                let status = an3155.extended_special(opcode, &data, &file_data)?;
+                println! {"Status: {}", format_bytes(&status)};
+            } else {
+                let response = an3155.special(opcode, &data)?;
+                println! {"Data: {}", format_bytes(&response.data)};
+                println! {"Status: {}", format_bytes(&response.status)};
+            }
+        }
+        Command::Protect => {
+            an3155.readout_protect()?;
+            println! {"Readout protection enabled"};
+        }
+        Command::Unprotect { confirm_mass_erase } => {
+            if !confirm_mass_erase {
+                invalid_input! {"Removing readout protection erases all flash memory.  Pass --confirm-mass-erase to continue"};
+            }
+            an3155.readout_unprotect()?;
+            println! {"Readout protection disabled, flash memory erased"};
+        }
+        Command::OptionBytes {
+            action: OptionBytesAction::Show,
+        } => {
+            let option_bytes = an3155.read_option_bytes()?;
+            println! {"{} option bytes at 0x{:08X}", option_bytes.layout.family, option_bytes.address};
+            for (field, value) in option_bytes.fields() {
+                println! {"  {:<16} 0x{value:<4X} {}", field.name, field.description};
+            }
+            for address in option_bytes.complement_errors() {
+                println! {"Warning: value at 0x{address:08X} does not match its complement, the device uses defaults"};
+            }
+        }
+        Command::OptionBytes {
+            action: OptionBytesAction::Set { fields },
+        } => {
+            let mut option_bytes = an3155.read_option_bytes()?;
+            for assignment in fields {
+                let Some((name, value)) = assignment.split_once('=') else {
+                    invalid_input! {"Option field must be given as NAME=VALUE, not {assignment}"};
+                };
+                let value = parse_number(value, "option field value")?;
+                option_bytes.set(name, value)?;
+            }
+            an3155
+                .write_option_bytes(&option_bytes)
+                .context("Device did not come back after writing option bytes")?;
+            for assignment in fields {
+                println! {"Set {assignment}"};
+            }
+            println! {"Option bytes written, device was reset to load them"};
         }
     }
 
+    if !started {
+        an3155.exit_bootloader()?;
+    }
     Ok(())
 }
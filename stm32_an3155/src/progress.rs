@@ -0,0 +1,72 @@
+use std::{
+    io::{IsTerminal, Write},
+    time::{Duration, Instant},
+};
+use stm32_an3155_rs::{Phase, Progress, ProgressObserver};
+
+/// Width of the bar, in characters
+const BAR_WIDTH: usize = 30;
+
+/// Minimum time between redraws of the bar
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Progress bar drawn on stdout, only when stdout is a terminal
+pub struct ProgressBar {
+    enabled: bool,
+    last_draw: Option<Instant>,
+}
+
+impl ProgressBar {
+    pub fn new() -> Self {
+        Self {
+            enabled: std::io::stdout().is_terminal(),
+            last_draw: None,
+        }
+    }
+}
+
+impl ProgressObserver for ProgressBar {
+    fn on_progress(&mut self, progress: &Progress) {
+        if !self.enabled {
+            return;
+        }
+        let finished = progress.done == progress.total;
+        let due = self
+            .last_draw
+            .is_none_or(|last| last.elapsed() >= REDRAW_INTERVAL);
+        if !finished && progress.done != 0 && !due {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+
+        let mut stdout = std::io::stdout().lock();
+        // Ignore errors, a broken progress bar is no reason to abort flashing
+        let _ = write!(stdout, "\r{}", format_progress(progress));
+        if finished {
+            let _ = writeln!(stdout);
+        }
+        let _ = stdout.flush();
+    }
+}
+
+/// Format one line of the progress bar
+fn format_progress(progress: &Progress) -> String {
+    let fraction = progress.fraction();
+    let filled = (fraction * BAR_WIDTH as f64) as usize;
+    let bar = format! {"{}{}", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled)};
+    let phase = match progress.phase {
+        Phase::Erase => "Erasing",
+        Phase::Write => "Writing",
+        Phase::Verify => "Verifying",
+        Phase::Read => "Reading",
+    };
+    let throughput = match progress.phase {
+        Phase::Erase => format! {"{:.1} pages/s", progress.throughput()},
+        _ => format! {"{:.1} KiB/s", progress.throughput() / 1024.0},
+    };
+    let eta = match progress.eta() {
+        Some(eta) => format! {"{}:{:02}", eta.as_secs() / 60, eta.as_secs() % 60},
+        None => "-:--".into(),
+    };
+    format! {"{phase:<9} [{bar}] {:>3}%  {throughput:>14}  ETA {eta}", (fraction * 100.0) as u32}
+}
@@ -0,0 +1,594 @@
+use std::{
+    fs,
+    net::TcpListener,
+    path::PathBuf,
+    process::{Command, Output},
+    thread::{self, JoinHandle},
+};
+use stm32_an3155_rs::{
+    sim::{Simulator, DEFAULT_COMMANDS},
+    BootloaderCommand, SpecialResponse,
+};
+
+/// Serve a simulated bootloader on a local TCP port
+fn serve(mut sim: Simulator) -> (String, JoinHandle<Simulator>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = format!("tcp://{}", listener.local_addr().unwrap());
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        sim.serve(stream).unwrap();
+        sim
+    });
+    (port, handle)
+}
+
+fn cli(port: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_stm32_an3155"))
+        .args(["--port", port])
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn info() {
+    let sim = Simulator::new()
+        .and_pid(0x0410)
+        .and_unknown_commands(&[0xB2])
+        .and_version_options([0x01, 0x02]);
+    let (port, sim) = serve(sim);
+
+    let output = cli(&port, &["info"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Product ID: 0x0410"), "{stdout}");
+    assert!(stdout.contains("Bootloader version: 2.2"), "{stdout}");
+    assert!(stdout.contains("STM32F10xxx medium-density"), "{stdout}");
+    assert!(stdout.contains("Option bytes: 01 02"), "{stdout}");
+    assert!(stdout.contains("ReadoutUnprotect, 0xB2"), "{stdout}");
+    sim.join().unwrap();
+}
+
+#[test]
+fn auto_baud_rate_requires_boot_pins() {
+    let output = cli("tcp://127.0.0.1:1", &["--baud", "auto", "info"]);
+    assert_eq!(Some(2), output.status.code(), "{output:?}");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("--boot-pins"), "{stderr}");
+}
+
+#[test]
+fn flash() {
+    let firmware: Vec<u8> = (0..1500u32).map(|x| (x * 7) as u8).collect();
+    let file = temp_file("flash.bin", &firmware);
+    let (port, sim) = serve(Simulator::new());
+
+    let output = cli(
+        &port,
+        &["flash", "--address", "0x08000400", file.to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{output:?}");
+    let sim = sim.join().unwrap();
+    assert_eq!(&firmware[..], &sim.flash()[0x400..0x400 + firmware.len()]);
+}
+
+#[test]
+fn flash_erases_device_pages() {
+    let file = temp_file("pages.bin", &[0x5A; 100]);
+    // stale data in the same 1 KiB page as the end of the firmware
+    let sim = Simulator::new().and_flash_contents(0x0800_0BF0, &[0u8; 0x20]);
+    let (port, sim) = serve(sim);
+
+    let output = cli(
+        &port,
+        &["flash", "-a", "0x08000BC0", file.to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{output:?}");
+    let sim = sim.join().unwrap();
+    assert_eq!(&[0x5A; 100][..], &sim.flash()[0xBC0..0xC24]);
+    assert!(sim.flash()[0xC24..0xC30].iter().all(|&b| b == 0xFF));
+}
+
+#[test]
+fn flash_unknown_device() {
+    let file = temp_file("unknown.bin", &[0x5A; 16]);
+    let (port, sim) = serve(Simulator::new().and_pid(0x0FFF));
+
+    let output = cli(&port, &["flash", file.to_str().unwrap()]);
+    assert!(!output.status.success());
+    sim.join().unwrap();
+
+    let (port, sim) = serve(Simulator::new().and_pid(0x0FFF));
+    let args = ["--page-size", "1024", "--flash-size", "128", "flash"];
+    let output = cli(&port, &[&args[..], &[file.to_str().unwrap()]].concat());
+    assert!(output.status.success(), "{output:?}");
+    sim.join().unwrap();
+}
+
+#[test]
+fn flash_layout_options_reject_invalid_sizes() {
+    let file = temp_file("layout.bin", &[0x5A; 16]);
+    let args = ["--page-size", "0", "--flash-size", "128", "flash"];
+    let output = cli(
+        "tcp://127.0.0.1:1",
+        &[&args[..], &[file.to_str().unwrap()]].concat(),
+    );
+    assert_eq!(Some(2), output.status.code(), "{output:?}");
+
+    let (port, sim) = serve(Simulator::new().and_pid(0x0FFF));
+    let args = ["--page-size", "1024", "--flash-size", "4194304", "flash"];
+    let output = cli(&port, &[&args[..], &[file.to_str().unwrap()]].concat());
+    sim.join().unwrap();
+    assert_eq!(Some(4), output.status.code(), "{output:?}");
+}
+
+#[test]
+fn flash_and_run() {
+    let file = temp_file("run.bin", &[0xAA; 64]);
+    let (port, sim) = serve(Simulator::new());
+
+    let output = cli(&port, &["flash", "--run", file.to_str().unwrap()]);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(Some(0x0800_0000), sim.join().unwrap().application_address());
+}
+
+#[test]
+fn go() {
+    let (port, sim) = serve(Simulator::new());
+
+    let output = cli(&port, &["go", "--address", "0x20000000"]);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(Some(0x2000_0000), sim.join().unwrap().application_address());
+}
+
+#[test]
+fn unprotect_requires_confirmation() {
+    let sim = Simulator::new().and_read_protection(true);
+    let (port, sim) = serve(sim);
+
+    let output = cli(&port, &["unprotect"]);
+    assert_eq!(Some(4), output.status.code(), "{output:?}");
+    assert!(sim.join().unwrap().read_protected());
+
+    let (port, sim) = serve(Simulator::new().and_read_protection(true));
+    let output = cli(&port, &["unprotect", "--confirm-mass-erase"]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(!sim.join().unwrap().read_protected());
+}
+
+#[test]
+fn write_protect() {
+    let (port, sim) = serve(Simulator::new());
+
+    let args = ["--page-size", "1024", "--flash-size", "128"];
+    let output = cli(&port, &[&args[..], &["write-protect", "0", "3"]].concat());
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(vec![0, 3], sim.join().unwrap().write_protected_pages());
+}
+
+#[test]
+fn flash_with_crc_verification() {
+    let commands = [DEFAULT_COMMANDS, &[BootloaderCommand::GetChecksum]].concat();
+    let file = temp_file("crc.bin", &[0x12; 301]);
+    let (port, sim) = serve(Simulator::new().and_commands(&commands));
+
+    let output = cli(
+        &port,
+        &[
+            "flash",
+            "--verify",
+            "crc",
+            "-a",
+            "0x08000002",
+            file.to_str().unwrap(),
+        ],
+    );
+    assert!(output.status.success(), "{output:?}");
+    sim.join().unwrap();
+
+    let (port, sim) = serve(Simulator::new());
+    let output = cli(&port, &["flash", "--verify", "crc", file.to_str().unwrap()]);
+    assert!(!output.status.success());
+    sim.join().unwrap();
+}
+
+#[test]
+fn special() {
+    let commands = [DEFAULT_COMMANDS, &[BootloaderCommand::Special]].concat();
+    let response = SpecialResponse {
+        data: vec![0xCA, 0xFE],
+        status: vec![0x01],
+    };
+    let sim = Simulator::new()
+        .and_commands(&commands)
+        .and_special(0x0042, response);
+    let (port, sim) = serve(sim);
+
+    let output = cli(&port, &["special", "--opcode", "0x42", "--data", "0a0b"]);
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Data: CAFE"), "{stdout}");
+    assert!(stdout.contains("Status: 01"), "{stdout}");
+    assert_eq!(
+        vec![0x0A, 0x0B],
+        sim.join().unwrap().special_requests()[0].data
+    );
+}
+
+#[test]
+fn special_rejects_non_ascii_data() {
+    let commands = [DEFAULT_COMMANDS, &[BootloaderCommand::Special]].concat();
+    let (port, sim) = serve(Simulator::new().and_commands(&commands));
+
+    let output = cli(&port, &["special", "--opcode", "0x42", "--data", "0é0"]);
+    assert_eq!(Some(4), output.status.code(), "{output:?}");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("expected hex digits"), "{stderr}");
+    assert!(sim.join().unwrap().special_requests().is_empty());
+}
+
+#[test]
+fn flash_intel_hex_segments() {
+    let hex = ":020000040800F2\n\
+               :0400000001020304F2\n\
+               :020800000909E4\n\
+               :00000001FF\n";
+    let file = temp_file("segments.hex", hex.as_bytes());
+    // data in a page between the two segments must survive
+    let sim = Simulator::new().and_flash_contents(0x0800_0400, &[0x11; 4]);
+    let (port, sim) = serve(sim);
+
+    let output = cli(&port, &["flash", file.to_str().unwrap()]);
+    assert!(output.status.success(), "{output:?}");
+    let sim = sim.join().unwrap();
+    assert_eq!(&[1, 2, 3, 4], &sim.flash()[..4]);
+    assert_eq!(&[0x11; 4], &sim.flash()[0x400..0x404]);
+    assert_eq!(&[9, 9], &sim.flash()[0x800..0x802]);
+}
+
+#[test]
+fn flash_elf_and_run_from_vector_table() {
+    // Initial stack pointer and reset vector pointing at the entry point
+    let code = [0x00, 0x50, 0x00, 0x20, 0x09, 0x01, 0x00, 0x08];
+    let mut file = b"\x7FELF\x01\x01\x01".to_vec();
+    file.resize(24, 0);
+    for word in [0x0800_0109u32, 52, 0, 0] {
+        file.extend(word.to_le_bytes()); // e_entry, e_phoff, e_shoff, e_flags
+    }
+    for half in [52u16, 32, 1, 0, 0, 0] {
+        file.extend(half.to_le_bytes());
+    }
+    for word in [1u32, 84, 0x0800_0100, 0x0800_0100, 8, 8, 5, 4] {
+        file.extend(word.to_le_bytes()); // PT_LOAD
+    }
+    file.extend(code);
+    let file = temp_file("firmware.elf", &file);
+    let (port, sim) = serve(Simulator::new());
+
+    let output = cli(&port, &["flash", "--run", file.to_str().unwrap()]);
+    assert!(output.status.success(), "{output:?}");
+    let sim = sim.join().unwrap();
+    assert_eq!(&code, &sim.flash()[0x100..0x108]);
+    assert_eq!(Some(0x0800_0100), sim.application_address());
+}
+
+#[test]
+fn flash_srec() {
+    let file = temp_file("firmware.s19", b"S309080001001122334443\nS70508000101F0\n");
+    let (port, sim) = serve(Simulator::new());
+
+    let output = cli(&port, &["flash", "--run", file.to_str().unwrap()]);
+    assert!(output.status.success(), "{output:?}");
+    let sim = sim.join().unwrap();
+    assert_eq!(&[0x11, 0x22, 0x33, 0x44], &sim.flash()[0x100..0x104]);
+    assert_eq!(Some(0x0800_0100), sim.application_address());
+}
+
+#[test]
+fn read_to_binary_file() {
+    let contents: Vec<u8> = (0..=255).cycle().take(600).collect();
+    let sim = Simulator::new().and_flash_contents(0x0800_0400, &contents);
+    let (port, sim) = serve(sim);
+    let output_file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("dump.bin");
+
+    let output = cli(
+        &port,
+        &[
+            "read",
+            "--address",
+            "0x08000400",
+            "--length",
+            "600",
+            output_file.to_str().unwrap(),
+        ],
+    );
+    assert!(output.status.success(), "{output:?}");
+    sim.join().unwrap();
+    assert_eq!(contents, fs::read(output_file).unwrap());
+}
+
+#[test]
+fn read_to_intel_hex_file() {
+    let sim = Simulator::new().and_flash_contents(0x0800_0000, &[1, 2, 3, 4]);
+    let (port, sim) = serve(sim);
+    let output_file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("dump.hex");
+
+    let output = cli(
+        &port,
+        &["read", "--length", "0x4", output_file.to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{output:?}");
+    sim.join().unwrap();
+    assert_eq!(
+        ":020000040800F2\n:0400000001020304F2\n:00000001FF\n",
+        fs::read_to_string(output_file).unwrap()
+    );
+}
+
+#[test]
+fn read_hexdump_to_stdout() {
+    let sim = Simulator::new().and_flash_contents(0x0800_0010, b"Hello, bootloader\x00");
+    let (port, sim) = serve(sim);
+
+    let output = cli(
+        &port,
+        &["read", "--address", "0x08000010", "--length", "18"],
+    );
+    assert!(output.status.success(), "{output:?}");
+    sim.join().unwrap();
+    assert_eq!(
+        "08000010  48 65 6C 6C 6F 2C 20 62 6F 6F 74 6C 6F 61 64 65  |Hello, bootloade|\n\
+         08000020  72 00                                            |r.|\n",
+        String::from_utf8(output.stdout).unwrap()
+    );
+}
+
+#[test]
+fn read_rejects_range_past_end_of_address_space() {
+    let (port, sim) = serve(Simulator::new());
+    let output = cli(
+        &port,
+        &["read", "--address", "0x08000000", "--length", "0xFFFFFFFF"],
+    );
+    sim.join().unwrap();
+    assert_eq!(Some(4), output.status.code(), "{output:?}");
+}
+
+#[test]
+fn erase_pages() {
+    let sim = Simulator::new().and_flash_contents(0x0800_0000, &[0u8; 4 * 1024]);
+    let (port, sim) = serve(sim);
+
+    let output = cli(&port, &["erase", "1", "3"]);
+    assert!(output.status.success(), "{output:?}");
+    let flash = sim.join().unwrap().flash().to_vec();
+    assert!(flash[..0x400].iter().all(|&b| b == 0x00));
+    assert!(flash[0x400..0x800].iter().all(|&b| b == 0xFF));
+    assert!(flash[0x800..0xC00].iter().all(|&b| b == 0x00));
+    assert!(flash[0xC00..0x1000].iter().all(|&b| b == 0xFF));
+}
+
+#[test]
+fn erase_rejects_pages_past_end_of_flash() {
+    // STM32F10xxx medium-density has 128 pages
+    let (port, sim) = serve(Simulator::new());
+    let output = cli(&port, &["erase", "1", "128"]);
+    sim.join().unwrap();
+    assert_eq!(Some(4), output.status.code(), "{output:?}");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Page 128"), "{stderr}");
+}
+
+#[test]
+fn erase_address_range_rounds_to_pages() {
+    let sim = Simulator::new().and_flash_contents(0x0800_0000, &[0u8; 4 * 1024]);
+    let (port, sim) = serve(sim);
+
+    let args = ["erase", "--address", "0x08000500", "--length", "0x400"];
+    let output = cli(&port, &args);
+    assert!(output.status.success(), "{output:?}");
+    let flash = sim.join().unwrap().flash().to_vec();
+    assert!(flash[..0x400].iter().all(|&b| b == 0x00));
+    assert!(flash[0x400..0xC00].iter().all(|&b| b == 0xFF));
+    assert!(flash[0xC00..0x1000].iter().all(|&b| b == 0x00));
+}
+
+#[test]
+fn erase_mass() {
+    let sim = Simulator::new().and_flash_contents(0x0800_0000, &[0u8; 128 * 1024]);
+    let (port, sim) = serve(sim);
+
+    let output = cli(&port, &["erase", "--mass"]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(sim.join().unwrap().flash().iter().all(|&b| b == 0xFF));
+}
+
+#[test]
+fn erase_bank() {
+    let commands = [
+        BootloaderCommand::Get,
+        BootloaderCommand::GetVersion,
+        BootloaderCommand::GetId,
+        BootloaderCommand::ExtendedErase,
+    ];
+    // STM32F10xxx XL-density has two banks
+    let sim = Simulator::new()
+        .and_pid(0x0430)
+        .and_commands(&commands)
+        .and_flash_contents(0x0800_0000, &[0u8; 128 * 1024]);
+    let (port, sim) = serve(sim);
+
+    let output = cli(&port, &["erase", "--bank", "2"]);
+    assert!(output.status.success(), "{output:?}");
+    let flash = sim.join().unwrap().flash().to_vec();
+    assert!(flash[..64 * 1024].iter().all(|&b| b == 0x00));
+    assert!(flash[64 * 1024..].iter().all(|&b| b == 0xFF));
+
+    // Standard erase has no bank erase
+    let (port, sim) = serve(Simulator::new().and_pid(0x0430));
+    let output = cli(&port, &["erase", "--bank", "1"]);
+    assert!(!output.status.success());
+    sim.join().unwrap();
+
+    // STM32F10xxx medium-density has one bank
+    let (port, sim) = serve(Simulator::new().and_commands(&commands));
+    let output = cli(&port, &["erase", "--bank", "2"]);
+    assert_eq!(Some(4), output.status.code(), "{output:?}");
+    sim.join().unwrap();
+}
+
+#[test]
+fn erase_requires_a_target() {
+    let output = cli("tcp://127.0.0.1:1", &["erase"]);
+    assert!(!output.status.success());
+    let output = cli("tcp://127.0.0.1:1", &["erase", "--mass", "3"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn verify_matching_image() {
+    let hex = b":020000040800F2\n:0400000001020304F2\n:00000001FF\n";
+    let file = temp_file("verify.hex", hex);
+    let sim = Simulator::new().and_flash_contents(0x0800_0000, &[1, 2, 3, 4]);
+    let (port, sim) = serve(sim);
+
+    let output = cli(&port, &["verify", file.to_str().unwrap()]);
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Verified 4 bytes"), "{stdout}");
+    let sim = sim.join().unwrap();
+    // Nothing was written
+    assert_eq!(&[1, 2, 3, 4], &sim.flash()[..4]);
+}
+
+#[test]
+fn verify_reports_mismatched_regions() {
+    let expected: Vec<u8> = (0..64).collect();
+    let file = temp_file("mismatch.bin", &expected);
+    let mut actual = expected.clone();
+    actual[3] = 0xEE;
+    actual[40..44].copy_from_slice(&[0xFF; 4]);
+    let sim = Simulator::new().and_flash_contents(0x0800_0000, &actual);
+    let (port, sim) = serve(sim);
+
+    let output = cli(&port, &["verify", file.to_str().unwrap()]);
+    sim.join().unwrap();
+    assert_eq!(Some(3), output.status.code());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        "Mismatch at 0x08000003 (1 bytes)\n  \
+           expected: 03\n  \
+           actual:   EE\n\
+         Mismatch at 0x08000028 (4 bytes)\n  \
+           expected: 28 29 2A 2B\n  \
+           actual:   FF FF FF FF\n",
+        stdout
+    );
+}
+
+#[test]
+fn exit_code_for_address_out_of_range() {
+    let file = temp_file("outside.bin", &[0x5A; 16]);
+    let (port, sim) = serve(Simulator::new());
+
+    let output = cli(
+        &port,
+        &["flash", "-a", "0x0801FFF8", file.to_str().unwrap()],
+    );
+    sim.join().unwrap();
+    assert_eq!(Some(4), output.status.code(), "{output:?}");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("out of range"), "{stderr}");
+}
+
+#[test]
+fn exit_code_for_missing_file() {
+    let (port, sim) = serve(Simulator::new());
+    let output = cli(&port, &["flash", "does-not-exist.bin"]);
+    sim.join().unwrap();
+    assert_eq!(Some(4), output.status.code(), "{output:?}");
+}
+
+#[test]
+fn exit_code_for_device_not_responding() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = format!("tcp://{}", listener.local_addr().unwrap());
+    let handle = thread::spawn(move || {
+        // Accept the connection but never answer
+        let (stream, _) = listener.accept().unwrap();
+        thread::sleep(std::time::Duration::from_millis(500));
+        drop(stream);
+    });
+
+    let output = cli(&port, &["--timeout-ms", "100", "info"]);
+    handle.join().unwrap();
+    assert_eq!(Some(5), output.status.code(), "{output:?}");
+}
+
+#[test]
+fn no_bootloader_on_silent_tcp_bridge() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = format!("tcp://{}", listener.local_addr().unwrap());
+    let handle = thread::spawn(move || {
+        // Swallow sync bytes without answering until the CLI disconnects
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut sink = Vec::new();
+        std::io::Read::read_to_end(&mut stream, &mut sink).unwrap_or_default()
+    });
+
+    let output = cli(&port, &["--timeout-ms", "200", "info"]);
+    let received = handle.join().unwrap();
+    assert_eq!(Some(5), output.status.code(), "{output:?}");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("no bootloader detected"), "{stderr}");
+    assert_eq!(3, received, "{stderr}");
+}
+
+#[test]
+fn option_bytes_show() {
+    let (port, sim) = serve(Simulator::new());
+
+    let output = cli(&port, &["option-bytes", "show"]);
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("STM32F1 option bytes at 0x1FFFF800"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("RDP              0xA5"), "{stdout}");
+    assert!(stdout.contains("WDG_SW           0x1"), "{stdout}");
+    assert!(!stdout.contains("Warning"), "{stdout}");
+    sim.join().unwrap();
+}
+
+#[test]
+fn option_bytes_set() {
+    let (port, sim) = serve(Simulator::new());
+
+    let output = cli(&port, &["option-bytes", "set", "WDG_SW=0", "DATA0=0x42"]);
+    assert!(output.status.success(), "{output:?}");
+    let sim = sim.join().unwrap();
+    assert_eq!(
+        [0xA5, 0x5A, 0xFE, 0x01, 0x42, 0xBD],
+        sim.option_bytes()[..6]
+    );
+}
+
+#[test]
+fn option_bytes_set_rejects_invalid_values() {
+    let (port, sim) = serve(Simulator::new());
+
+    let output = cli(&port, &["option-bytes", "set", "RDP=0xCC"]);
+    assert_eq!(Some(4), output.status.code(), "{output:?}");
+    let (port, sim) = serve(sim.join().unwrap());
+    let output = cli(&port, &["option-bytes", "set", "WDG_SW"]);
+    assert_eq!(Some(4), output.status.code(), "{output:?}");
+    let sim = sim.join().unwrap();
+    assert_eq!(0xA5, sim.option_bytes()[0]);
+}
@@ -0,0 +1,183 @@
+use crate::{
+    error::Context, ControlSequence, Error, Image, Result, Transport, AN3155, DEFAULT_START_ADDRESS,
+};
+use log::{debug, info, warn};
+
+/// Baud rates tried by the default [`BaudProbe`], fastest first
+pub const DEFAULT_PROBE_BAUD_RATES: &[u32] = &[
+    921_600, 460_800, 230_400, 115_200, 57_600, 38_400, 19_200, 9_600,
+];
+
+/// Number of bytes transferred by the default stress check
+pub const DEFAULT_STRESS_LEN: usize = 1024;
+
+/// Transfer that must succeed for a baud rate to be accepted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StressCheck {
+    /// Write a test pattern of up to `len` bytes to the start of the RAM
+    /// available to the host, looked up from the product ID, and read it
+    /// back.  If the device is unknown, read `len` bytes at the start of
+    /// flash twice and compare instead.
+    DeviceRam { len: usize },
+    /// Read `len` bytes at `address` twice and compare
+    Read { address: u32, len: usize },
+    /// Write a test pattern of `len` bytes to RAM at `address` and read it back
+    Write { address: u32, len: usize },
+}
+
+/// Baud rates to try when initializing, and how each one is checked
+///
+/// Rates are tried fastest first.  At each rate the session synchronizes,
+/// asks for the bootloader version and runs the stress check, if any.
+///
+/// The bootloader locks to the baud rate of the first sync byte it receives,
+/// so it has to be reset between rates with an entry sequence, e.g. from
+/// [`Builder::and_boot_pins`](crate::Builder::and_boot_pins).  Without one,
+/// probing stops at the first rate the bootloader synchronizes at.
+///
+/// # Example
+/// ```
+/// # use stm32_an3155_rs::{sim::Simulator, BaudProbe, BootPins, Builder};
+/// let pins = BootPins::default().and_timing(Default::default(), Default::default());
+/// let sim = Simulator::new()
+///     .and_boot_pins(pins.clone())
+///     .and_max_baud_rate(115_200);
+///
+/// let an3155 = Builder::with_transport(sim)
+///     .and_boot_pins(pins)
+///     .and_baud_probe(BaudProbe::default())
+///     .initialize()?;
+/// assert_eq!(Some(115_200), an3155.baud_rate());
+/// # Ok::<(), stm32_an3155_rs::Error>(())
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BaudProbe {
+    pub rates: Vec<u32>,
+    pub check: Option<StressCheck>,
+}
+
+impl Default for BaudProbe {
+    fn default() -> Self {
+        Self::new(DEFAULT_PROBE_BAUD_RATES)
+    }
+}
+
+impl BaudProbe {
+    /// Probe the given rates with the default stress check
+    pub fn new(rates: &[u32]) -> Self {
+        Self {
+            rates: rates.to_vec(),
+            check: Some(StressCheck::DeviceRam {
+                len: DEFAULT_STRESS_LEN,
+            }),
+        }
+    }
+
+    /// Replace the stress check, or only check the GetVersion round-trip
+    pub fn and_check(mut self, check: Option<StressCheck>) -> Self {
+        self.check = check;
+        self
+    }
+}
+
+impl<T: Transport> AN3155<T> {
+    /// Synchronize at the fastest rate that passes the checks of `probe`
+    ///
+    /// Commands are not retried while probing, so that a rate that only
+    /// works with retries is rejected.
+    pub(crate) fn probe_baud_rate(
+        &mut self,
+        probe: &BaudProbe,
+        entry_sequence: Option<&ControlSequence>,
+    ) -> Result<u32> {
+        let mut rates = probe.rates.clone();
+        rates.sort_unstable_by(|a, b| b.cmp(a));
+        rates.dedup();
+
+        let retry_policy = std::mem::take(&mut self.retry_policy);
+        let result = self.probe_rates(&rates, probe.check, entry_sequence);
+        self.retry_policy = retry_policy;
+        result
+    }
+
+    fn probe_rates(
+        &mut self,
+        rates: &[u32],
+        check: Option<StressCheck>,
+        entry_sequence: Option<&ControlSequence>,
+    ) -> Result<u32> {
+        for &rate in rates {
+            info! {"probing baud rate {rate}"};
+            self.serial
+                .set_baud(rate)
+                .context("Failed to set transport baud rate")?;
+            self.baud_rate = Some(rate);
+            self.enter_bootloader(entry_sequence)?;
+
+            let error = match self.sync().and_then(|()| self.check_link(check)) {
+                Ok(()) => {
+                    info! {"using baud rate {rate}"};
+                    return Ok(rate);
+                }
+                Err(error) => error,
+            };
+            warn! {"baud rate {rate} failed: {error}"};
+            let synced = !matches!(error.root(), Error::NoBootloader { .. });
+            if synced && entry_sequence.is_none() {
+                warn! {"bootloader is locked to {rate} baud, an entry sequence is needed to reset it"};
+                break;
+            }
+        }
+        Err(Error::NoBaudRate {
+            rates: rates.to_vec(),
+        })
+    }
+
+    /// Check that commands get through at the current baud rate
+    fn check_link(&mut self, check: Option<StressCheck>) -> Result<()> {
+        self.get_version()?;
+        match check {
+            Some(check) => self.stress(check),
+            None => Ok(()),
+        }
+    }
+
+    fn stress(&mut self, check: StressCheck) -> Result<()> {
+        debug! {"link check: {check:?}"};
+        match check {
+            StressCheck::DeviceRam { len } => {
+                let check = match self.get_device()? {
+                    Some(device) => StressCheck::Write {
+                        address: device.ram.start,
+                        len: len.min(device.ram.len()),
+                    },
+                    None => StressCheck::Read {
+                        address: DEFAULT_START_ADDRESS,
+                        len,
+                    },
+                };
+                return self.stress(check);
+            }
+            StressCheck::Read { address, len } => {
+                let mut first = vec![0u8; len];
+                let mut second = vec![0u8; len];
+                self.read_region(address, &mut first, &mut ())?;
+                self.read_region(address, &mut second, &mut ())?;
+                if let Some(offset) = first.iter().zip(&second).position(|(a, b)| a != b) {
+                    let address = address + offset as u32;
+                    return Err(Error::VerifyMismatch { address });
+                }
+            }
+            StressCheck::Write { address, len } => {
+                let pattern = (0..len).map(|i| (i as u8) ^ 0xA5).collect();
+                let image = Image::from_binary(address, pattern);
+                self.write_image(&image, &mut ())?;
+                if let Some(mismatch) = self.verify_image(&image, &mut ())?.first() {
+                    let address = mismatch.address;
+                    return Err(Error::VerifyMismatch { address });
+                }
+            }
+        }
+        Ok(())
+    }
+}
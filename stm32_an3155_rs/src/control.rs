@@ -0,0 +1,168 @@
+use crate::Transport;
+use log::debug;
+use std::{io::Result as IoResult, thread, time::Duration};
+
+/// Default time the reset line is held active
+pub const DEFAULT_RESET_PULSE: Duration = Duration::from_millis(50);
+
+/// Default time to wait for the chip to boot after reset is released
+pub const DEFAULT_BOOT_DELAY: Duration = Duration::from_millis(100);
+
+/// Modem control line of a serial port
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlLine {
+    /// Data Terminal Ready
+    Dtr,
+    /// Request To Send
+    Rts,
+}
+
+/// Step of a [`ControlSequence`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlStep {
+    /// Assert (`true`) or deassert (`false`) a control line
+    Set { line: ControlLine, level: bool },
+    /// Wait before the next step
+    Delay(Duration),
+}
+
+/// Steps driving the serial control lines, e.g. to reset the chip into the
+/// bootloader
+///
+/// # Example
+/// ```
+/// # use std::time::Duration;
+/// # use stm32_an3155_rs::{ControlLine, ControlSequence};
+/// let pulse = ControlSequence::new()
+///     .and_set(ControlLine::Dtr, true)
+///     .and_delay(Duration::from_millis(10))
+///     .and_set(ControlLine::Dtr, false);
+/// assert_eq!(3, pulse.steps.len());
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ControlSequence {
+    pub steps: Vec<ControlStep>,
+}
+
+impl ControlSequence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn and_set(mut self, line: ControlLine, level: bool) -> Self {
+        self.steps.push(ControlStep::Set { line, level });
+        self
+    }
+
+    pub fn and_delay(mut self, delay: Duration) -> Self {
+        self.steps.push(ControlStep::Delay(delay));
+        self
+    }
+
+    /// Drive the control lines of `transport` through every step
+    pub fn run<T: Transport + ?Sized>(&self, transport: &mut T) -> IoResult<()> {
+        for step in &self.steps {
+            debug! {"control sequence step: {step:?}"};
+            match *step {
+                ControlStep::Set { line, level } => transport.set_control_line(line, level)?,
+                ControlStep::Delay(delay) => thread::sleep(delay),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wiring of the chip reset and BOOT0 pins to serial control lines
+///
+/// The default matches stm32flash: DTR drives NRST and RTS drives BOOT0,
+/// with an asserted line holding the chip in reset or selecting the
+/// bootloader.  Set the `invert_*` flags when the board inverts a line.
+///
+/// # Example
+/// ```
+/// # use stm32_an3155_rs::{sim::Simulator, BootPins, Builder};
+/// let pins = BootPins::default().and_timing(Default::default(), Default::default());
+/// let sim = Simulator::new().and_boot_pins(pins.clone());
+/// assert!(Builder::with_transport(sim).initialize().is_err());
+///
+/// let sim = Simulator::new().and_boot_pins(pins.clone());
+/// let mut an3155 = Builder::with_transport(sim).and_boot_pins(pins).initialize()?;
+/// an3155.exit_bootloader()?;
+/// assert_eq!(Some(0x0800_0000), an3155.transport().application_address());
+/// # Ok::<(), stm32_an3155_rs::Error>(())
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BootPins {
+    /// Line connected to NRST
+    pub reset: ControlLine,
+    /// Line connected to BOOT0
+    pub boot0: ControlLine,
+    pub invert_reset: bool,
+    pub invert_boot0: bool,
+    /// Time reset is held active
+    pub reset_pulse: Duration,
+    /// Time to wait for the chip to boot after reset is released
+    pub boot_delay: Duration,
+}
+
+impl Default for BootPins {
+    fn default() -> Self {
+        Self {
+            reset: ControlLine::Dtr,
+            boot0: ControlLine::Rts,
+            invert_reset: false,
+            invert_boot0: false,
+            reset_pulse: DEFAULT_RESET_PULSE,
+            boot_delay: DEFAULT_BOOT_DELAY,
+        }
+    }
+}
+
+impl BootPins {
+    pub fn and_lines(mut self, reset: ControlLine, boot0: ControlLine) -> Self {
+        self.reset = reset;
+        self.boot0 = boot0;
+        self
+    }
+
+    pub fn and_inverted(mut self, invert_reset: bool, invert_boot0: bool) -> Self {
+        self.invert_reset = invert_reset;
+        self.invert_boot0 = invert_boot0;
+        self
+    }
+
+    pub fn and_timing(mut self, reset_pulse: Duration, boot_delay: Duration) -> Self {
+        self.reset_pulse = reset_pulse;
+        self.boot_delay = boot_delay;
+        self
+    }
+
+    /// Level of the reset line that holds the chip in reset, or releases it
+    pub fn reset_level(&self, active: bool) -> bool {
+        active != self.invert_reset
+    }
+
+    /// Level of the BOOT0 line that selects the bootloader, or flash
+    pub fn boot0_level(&self, active: bool) -> bool {
+        active != self.invert_boot0
+    }
+
+    /// Select the bootloader and pulse reset
+    pub fn entry(&self) -> ControlSequence {
+        self.reset_with_boot0(true)
+    }
+
+    /// Select flash and pulse reset, starting the application
+    pub fn exit(&self) -> ControlSequence {
+        self.reset_with_boot0(false)
+    }
+
+    fn reset_with_boot0(&self, boot0: bool) -> ControlSequence {
+        ControlSequence::new()
+            .and_set(self.boot0, self.boot0_level(boot0))
+            .and_set(self.reset, self.reset_level(true))
+            .and_delay(self.reset_pulse)
+            .and_set(self.reset, self.reset_level(false))
+            .and_delay(self.boot_delay)
+    }
+}
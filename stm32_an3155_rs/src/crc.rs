@@ -0,0 +1,40 @@
+/// Polynomial used by the STM32 hardware CRC unit (CRC-32/MPEG-2)
+pub const CRC32_POLYNOMIAL: u32 = 0x04C1_1DB7;
+
+/// Reset value of the STM32 hardware CRC unit
+pub const CRC32_INITIAL: u32 = 0xFFFF_FFFF;
+
+/// Compute a CRC the same way as the STM32 hardware CRC unit in its default configuration
+///
+/// # Example
+/// ```
+/// # use stm32_an3155_rs::crc32;
+/// assert_eq!(0xDF8A_8A2B, crc32(&0x1234_5678u32.to_le_bytes()));
+/// ```
+///
+/// # Panics
+/// Panics if the length of `data` is not a multiple of 4.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_with_polynomial(CRC32_POLYNOMIAL, CRC32_INITIAL, data)
+}
+
+/// Compute a CRC the same way as the STM32 hardware CRC unit
+///
+/// Memory is fed to the CRC unit as 32-bit little endian words, and each word
+/// is processed most significant bit first with no bit reversal or final XOR.
+///
+/// # Panics
+/// Panics if the length of `data` is not a multiple of 4.
+pub fn crc32_with_polynomial(polynomial: u32, initial: u32, data: &[u8]) -> u32 {
+    assert!(
+        data.len().is_multiple_of(4),
+        "CRC data length must be a multiple of 4 bytes"
+    );
+    data.chunks_exact(4).fold(initial, |crc, word| {
+        let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        (0..32).fold(crc ^ word, |crc, _| match crc & 0x8000_0000 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ polynomial,
+        })
+    })
+}
@@ -0,0 +1,434 @@
+use crate::{
+    option_bytes::{
+        F0_OPTION_LAYOUT, F1_OPTION_LAYOUT, F3_OPTION_LAYOUT, F4_OPTION_LAYOUT, G0_OPTION_LAYOUT,
+        G4_OPTION_LAYOUT, L0_OPTION_LAYOUT, L1_OPTION_LAYOUT, L4_OPTION_LAYOUT,
+    },
+    FlashLayout, OptionBytesLayout, Sectors,
+};
+
+use std::ops::Range;
+
+/// Memory map of an STM32 product, looked up by the product ID returned by GetId
+///
+/// Addresses and sizes are taken from AN2606 and the family reference
+/// manuals.  Where a product ID covers parts with different flash sizes, the
+/// largest size is listed.
+///
+/// # Example
+/// ```
+/// # use stm32_an3155_rs::Device;
+/// let device = Device::from_pid(0x0413).unwrap();
+///
+/// assert_eq!("STM32F40xxx/41xxx", device.name);
+/// assert_eq!(1024 * 1024, device.flash.size());
+/// assert_eq!(12, device.flash.sector_count());
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Device {
+    /// Product ID
+    pub pid: u16,
+    /// Part family and line
+    pub name: &'static str,
+    /// Flash memory layout
+    pub flash: FlashLayout,
+    /// Number of flash banks
+    pub banks: u8,
+    /// RAM available to the host while the bootloader is running
+    pub ram: Range<u32>,
+    /// Start address of the option bytes
+    pub option_bytes: u32,
+    /// Encoding of the option bytes, if known
+    pub option_layout: Option<&'static OptionBytesLayout>,
+    /// Start address of system memory, where the bootloader lives
+    pub system_memory: u32,
+}
+
+impl Device {
+    /// Look up a device by product ID
+    pub fn from_pid(pid: u16) -> Option<&'static Device> {
+        DEVICES.iter().find(|d| d.pid == pid)
+    }
+}
+
+const FLASH_BASE: u32 = 0x0800_0000;
+
+const fn pages(size: u32, count: u32) -> Sectors {
+    Sectors { size, count }
+}
+
+const KIB: u32 = 1024;
+
+const F1_OPTION_BYTES: u32 = 0x1FFF_F800;
+const F4_OPTION_BYTES: u32 = 0x1FFF_C000;
+const F4_SYSTEM_MEMORY: u32 = 0x1FFF_0000;
+const G0_OPTION_BYTES: u32 = 0x1FFF_7800;
+const G0_SYSTEM_MEMORY: u32 = 0x1FFF_0000;
+const L0_OPTION_BYTES: u32 = 0x1FF8_0000;
+const L0_SYSTEM_MEMORY: u32 = 0x1FF0_0000;
+
+/// 4 x 16 KiB, 1 x 64 KiB and `n` x 128 KiB sectors
+macro_rules! f4_sectors {
+    ($n:expr) => {
+        &[pages(16 * KIB, 4), pages(64 * KIB, 1), pages(128 * KIB, $n)]
+    };
+}
+
+/// Built-in device table
+pub const DEVICES: &[Device] = &[
+    // STM32F0
+    Device {
+        pid: 0x0444,
+        name: "STM32F03xx4/6",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(KIB, 32)]).and_wrp_sectors(4, 8),
+        banks: 1,
+        ram: 0x2000_0800..0x2000_1000,
+        option_bytes: F1_OPTION_BYTES,
+        option_layout: Some(&F0_OPTION_LAYOUT),
+        system_memory: 0x1FFF_EC00,
+    },
+    Device {
+        pid: 0x0445,
+        name: "STM32F04xxx/F070x6",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(KIB, 32)]).and_wrp_sectors(4, 8),
+        banks: 1,
+        ram: 0x2000_0800..0x2000_1800,
+        option_bytes: F1_OPTION_BYTES,
+        option_layout: Some(&F0_OPTION_LAYOUT),
+        system_memory: 0x1FFF_C400,
+    },
+    Device {
+        pid: 0x0440,
+        name: "STM32F030x8/F05xxx",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(KIB, 64)]).and_wrp_sectors(4, 16),
+        banks: 1,
+        ram: 0x2000_0800..0x2000_2000,
+        option_bytes: F1_OPTION_BYTES,
+        option_layout: Some(&F0_OPTION_LAYOUT),
+        system_memory: 0x1FFF_EC00,
+    },
+    Device {
+        pid: 0x0448,
+        name: "STM32F070xB/F071xx/F072xx",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 64)]).and_wrp_sectors(2, 32),
+        banks: 1,
+        ram: 0x2000_1800..0x2000_4000,
+        option_bytes: F1_OPTION_BYTES,
+        option_layout: Some(&F0_OPTION_LAYOUT),
+        system_memory: 0x1FFF_C800,
+    },
+    Device {
+        pid: 0x0442,
+        name: "STM32F030xC/F09xxx",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 128)]).and_wrp_sectors(4, 32),
+        banks: 1,
+        ram: 0x2000_1800..0x2000_8000,
+        option_bytes: F1_OPTION_BYTES,
+        option_layout: Some(&F0_OPTION_LAYOUT),
+        system_memory: 0x1FFF_D800,
+    },
+    // STM32F1
+    Device {
+        pid: 0x0412,
+        name: "STM32F10xxx low-density",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(KIB, 32)]).and_wrp_sectors(4, 8),
+        banks: 1,
+        ram: 0x2000_0200..0x2000_2800,
+        option_bytes: F1_OPTION_BYTES,
+        option_layout: Some(&F1_OPTION_LAYOUT),
+        system_memory: 0x1FFF_F000,
+    },
+    Device {
+        pid: 0x0410,
+        name: "STM32F10xxx medium-density",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(KIB, 128)]).and_wrp_sectors(4, 32),
+        banks: 1,
+        ram: 0x2000_0200..0x2000_5000,
+        option_bytes: F1_OPTION_BYTES,
+        option_layout: Some(&F1_OPTION_LAYOUT),
+        system_memory: 0x1FFF_F000,
+    },
+    Device {
+        pid: 0x0414,
+        name: "STM32F10xxx high-density",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 256)]).and_wrp_sectors(2, 32),
+        banks: 1,
+        ram: 0x2000_0200..0x2001_0000,
+        option_bytes: F1_OPTION_BYTES,
+        option_layout: Some(&F1_OPTION_LAYOUT),
+        system_memory: 0x1FFF_F000,
+    },
+    Device {
+        pid: 0x0420,
+        name: "STM32F100xx medium-density value line",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(KIB, 128)]).and_wrp_sectors(4, 32),
+        banks: 1,
+        ram: 0x2000_0200..0x2000_2000,
+        option_bytes: F1_OPTION_BYTES,
+        option_layout: Some(&F1_OPTION_LAYOUT),
+        system_memory: 0x1FFF_F000,
+    },
+    Device {
+        pid: 0x0428,
+        name: "STM32F100xx high-density value line",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 256)]).and_wrp_sectors(2, 32),
+        banks: 1,
+        ram: 0x2000_0200..0x2000_8000,
+        option_bytes: F1_OPTION_BYTES,
+        option_layout: Some(&F1_OPTION_LAYOUT),
+        system_memory: 0x1FFF_F000,
+    },
+    Device {
+        pid: 0x0418,
+        name: "STM32F105xx/F107xx connectivity line",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 128)]).and_wrp_sectors(2, 32),
+        banks: 1,
+        ram: 0x2000_1000..0x2001_0000,
+        option_bytes: F1_OPTION_BYTES,
+        option_layout: Some(&F1_OPTION_LAYOUT),
+        system_memory: 0x1FFF_B000,
+    },
+    Device {
+        pid: 0x0430,
+        name: "STM32F10xxx XL-density",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 512)]).and_wrp_sectors(2, 32),
+        banks: 2,
+        ram: 0x2000_0800..0x2001_8000,
+        option_bytes: F1_OPTION_BYTES,
+        option_layout: Some(&F1_OPTION_LAYOUT),
+        system_memory: 0x1FFF_E000,
+    },
+    // STM32F3
+    Device {
+        pid: 0x0438,
+        name: "STM32F303x4/6/8/F334xx/F328xx",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 32)]).and_wrp_sectors(2, 16),
+        banks: 1,
+        ram: 0x2000_1800..0x2000_3000,
+        option_bytes: F1_OPTION_BYTES,
+        option_layout: Some(&F3_OPTION_LAYOUT),
+        system_memory: 0x1FFF_D800,
+    },
+    Device {
+        pid: 0x0422,
+        name: "STM32F302xB/C/F303xB/C/F358xx",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 128)]).and_wrp_sectors(2, 32),
+        banks: 1,
+        ram: 0x2000_1400..0x2000_A000,
+        option_bytes: F1_OPTION_BYTES,
+        option_layout: Some(&F3_OPTION_LAYOUT),
+        system_memory: 0x1FFF_D800,
+    },
+    // STM32F4
+    Device {
+        pid: 0x0413,
+        name: "STM32F40xxx/41xxx",
+        flash: FlashLayout::new(FLASH_BASE, f4_sectors!(7)),
+        banks: 1,
+        ram: 0x2000_3000..0x2002_0000,
+        option_bytes: F4_OPTION_BYTES,
+        option_layout: Some(&F4_OPTION_LAYOUT),
+        system_memory: F4_SYSTEM_MEMORY,
+    },
+    Device {
+        pid: 0x0419,
+        name: "STM32F42xxx/43xxx",
+        flash: FlashLayout::new(
+            FLASH_BASE,
+            &[
+                pages(16 * KIB, 4),
+                pages(64 * KIB, 1),
+                pages(128 * KIB, 7),
+                pages(16 * KIB, 4),
+                pages(64 * KIB, 1),
+                pages(128 * KIB, 7),
+            ],
+        ),
+        banks: 2,
+        ram: 0x2000_3000..0x2003_0000,
+        option_bytes: F4_OPTION_BYTES,
+        option_layout: Some(&F4_OPTION_LAYOUT),
+        system_memory: F4_SYSTEM_MEMORY,
+    },
+    Device {
+        pid: 0x0423,
+        name: "STM32F401xB/C",
+        flash: FlashLayout::new(FLASH_BASE, f4_sectors!(1)),
+        banks: 1,
+        ram: 0x2000_3000..0x2001_0000,
+        option_bytes: F4_OPTION_BYTES,
+        option_layout: Some(&F4_OPTION_LAYOUT),
+        system_memory: F4_SYSTEM_MEMORY,
+    },
+    Device {
+        pid: 0x0433,
+        name: "STM32F401xD/E",
+        flash: FlashLayout::new(FLASH_BASE, f4_sectors!(3)),
+        banks: 1,
+        ram: 0x2000_3000..0x2001_8000,
+        option_bytes: F4_OPTION_BYTES,
+        option_layout: Some(&F4_OPTION_LAYOUT),
+        system_memory: F4_SYSTEM_MEMORY,
+    },
+    Device {
+        pid: 0x0431,
+        name: "STM32F411xx",
+        flash: FlashLayout::new(FLASH_BASE, f4_sectors!(3)),
+        banks: 1,
+        ram: 0x2000_3000..0x2002_0000,
+        option_bytes: F4_OPTION_BYTES,
+        option_layout: Some(&F4_OPTION_LAYOUT),
+        system_memory: F4_SYSTEM_MEMORY,
+    },
+    Device {
+        pid: 0x0441,
+        name: "STM32F412xx",
+        flash: FlashLayout::new(FLASH_BASE, f4_sectors!(7)),
+        banks: 1,
+        ram: 0x2000_3000..0x2004_0000,
+        option_bytes: F4_OPTION_BYTES,
+        option_layout: Some(&F4_OPTION_LAYOUT),
+        system_memory: F4_SYSTEM_MEMORY,
+    },
+    Device {
+        pid: 0x0421,
+        name: "STM32F446xx",
+        flash: FlashLayout::new(FLASH_BASE, f4_sectors!(3)),
+        banks: 1,
+        ram: 0x2000_3000..0x2002_0000,
+        option_bytes: F4_OPTION_BYTES,
+        option_layout: Some(&F4_OPTION_LAYOUT),
+        system_memory: F4_SYSTEM_MEMORY,
+    },
+    // STM32F7
+    Device {
+        pid: 0x0449,
+        name: "STM32F74xxx/75xxx",
+        flash: FlashLayout::new(
+            FLASH_BASE,
+            &[pages(32 * KIB, 4), pages(128 * KIB, 1), pages(256 * KIB, 3)],
+        ),
+        banks: 1,
+        ram: 0x2000_4000..0x2005_0000,
+        option_bytes: 0x1FFF_0000,
+        option_layout: None,
+        system_memory: 0x1FF0_0000,
+    },
+    Device {
+        pid: 0x0451,
+        name: "STM32F76xxx/77xxx",
+        flash: FlashLayout::new(
+            FLASH_BASE,
+            &[pages(32 * KIB, 4), pages(128 * KIB, 1), pages(256 * KIB, 7)],
+        ),
+        banks: 1,
+        ram: 0x2000_4000..0x2008_0000,
+        option_bytes: 0x1FFF_0000,
+        option_layout: None,
+        system_memory: 0x1FF0_0000,
+    },
+    // STM32G0
+    Device {
+        pid: 0x0466,
+        name: "STM32G03xxx/04xxx",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 32)]),
+        banks: 1,
+        ram: 0x2000_1000..0x2000_2000,
+        option_bytes: G0_OPTION_BYTES,
+        option_layout: Some(&G0_OPTION_LAYOUT),
+        system_memory: G0_SYSTEM_MEMORY,
+    },
+    Device {
+        pid: 0x0460,
+        name: "STM32G07xxx/08xxx",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 64)]),
+        banks: 1,
+        ram: 0x2000_2700..0x2000_9000,
+        option_bytes: G0_OPTION_BYTES,
+        option_layout: Some(&G0_OPTION_LAYOUT),
+        system_memory: G0_SYSTEM_MEMORY,
+    },
+    // STM32G4
+    Device {
+        pid: 0x0468,
+        name: "STM32G431xx/441xx",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 64)]),
+        banks: 1,
+        ram: 0x2000_4000..0x2000_5800,
+        option_bytes: G0_OPTION_BYTES,
+        option_layout: Some(&G4_OPTION_LAYOUT),
+        system_memory: G0_SYSTEM_MEMORY,
+    },
+    Device {
+        pid: 0x0469,
+        name: "STM32G47xxx/48xxx",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 256)]),
+        banks: 2,
+        ram: 0x2000_4000..0x2002_0000,
+        option_bytes: G0_OPTION_BYTES,
+        option_layout: Some(&G4_OPTION_LAYOUT),
+        system_memory: G0_SYSTEM_MEMORY,
+    },
+    // STM32L0
+    Device {
+        pid: 0x0417,
+        name: "STM32L05xxx/06xxx",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(128, 512)]).and_wrp_sectors(32, 16),
+        banks: 1,
+        ram: 0x2000_1000..0x2000_2000,
+        option_bytes: L0_OPTION_BYTES,
+        option_layout: Some(&L0_OPTION_LAYOUT),
+        system_memory: L0_SYSTEM_MEMORY,
+    },
+    Device {
+        pid: 0x0447,
+        name: "STM32L07xxx/08xxx",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(128, 1536)]).and_wrp_sectors(32, 48),
+        banks: 2,
+        ram: 0x2000_1000..0x2000_5000,
+        option_bytes: L0_OPTION_BYTES,
+        option_layout: Some(&L0_OPTION_LAYOUT),
+        system_memory: L0_SYSTEM_MEMORY,
+    },
+    // STM32L1
+    Device {
+        pid: 0x0416,
+        name: "STM32L1xxx6/8/B",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(256, 512)]).and_wrp_sectors(16, 32),
+        banks: 1,
+        ram: 0x2000_0800..0x2000_4000,
+        option_bytes: L0_OPTION_BYTES,
+        option_layout: Some(&L1_OPTION_LAYOUT),
+        system_memory: L0_SYSTEM_MEMORY,
+    },
+    // STM32L4
+    Device {
+        pid: 0x0435,
+        name: "STM32L43xxx/44xxx",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 128)]),
+        banks: 1,
+        ram: 0x2000_3000..0x2000_C000,
+        option_bytes: G0_OPTION_BYTES,
+        option_layout: Some(&L4_OPTION_LAYOUT),
+        system_memory: G0_SYSTEM_MEMORY,
+    },
+    Device {
+        pid: 0x0462,
+        name: "STM32L45xxx/46xxx",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 256)]),
+        banks: 1,
+        ram: 0x2000_3100..0x2002_0000,
+        option_bytes: G0_OPTION_BYTES,
+        option_layout: Some(&L4_OPTION_LAYOUT),
+        system_memory: G0_SYSTEM_MEMORY,
+    },
+    Device {
+        pid: 0x0415,
+        name: "STM32L47xxx/48xxx",
+        flash: FlashLayout::new(FLASH_BASE, &[pages(2 * KIB, 512)]),
+        banks: 2,
+        ram: 0x2000_3000..0x2001_8000,
+        option_bytes: G0_OPTION_BYTES,
+        option_layout: Some(&L4_OPTION_LAYOUT),
+        system_memory: G0_SYSTEM_MEMORY,
+    },
+];
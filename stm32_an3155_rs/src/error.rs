@@ -0,0 +1,143 @@
+use crate::BootloaderCommand;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use thiserror::Error as ThisError;
+
+/// Result type returned by the library
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] IoError),
+
+    #[error(transparent)]
+    Serial(#[from] serialport::Error),
+
+    #[error("no bootloader detected, {attempts} sync attempts went unanswered")]
+    NoBootloader { attempts: u32 },
+
+    #[error("no baud rate out of {rates:?} passed the link check")]
+    NoBaudRate { rates: Vec<u32> },
+
+    #[error("invalid response from bootloader: 0x{0:02X}")]
+    InvalidResponse(u8),
+
+    #[error("received a NACK from bootloader for {0:?} command")]
+    Nack(BootloaderCommand),
+
+    #[error("invalid bootloader command: 0x{0:02X}")]
+    InvalidBootloaderCommand(u8),
+
+    #[error("bootloader does not support the {0:?} command")]
+    Unsupported(BootloaderCommand),
+
+    #[error("Erase command supports only up to 254 pages.  Provided {0}")]
+    ErasePageCount(usize),
+
+    #[error("Write command supports only up to 256 bytes.  Provided {0}")]
+    WriteBytesCount(usize),
+
+    #[error("Write protect command supports only up to 256 sectors.  Provided {0}")]
+    WriteProtectSectorCount(usize),
+
+    #[error("Special command supports only up to {max} data bytes.  Provided {len}")]
+    SpecialDataCount { len: usize, max: usize },
+
+    #[error("Checksum command requires a non-zero size that is a multiple of 4.  Provided {0}")]
+    ChecksumSize(u32),
+
+    #[error("checksum of bootloader response does not match")]
+    ResponseChecksum,
+
+    #[error("bootloader response has {len} bytes, expected {expected}")]
+    ResponseLength { len: usize, expected: usize },
+
+    #[error("invalid firmware image: {0}")]
+    InvalidImage(String),
+
+    #[error("Sector {sector} does not exist, flash has {count} sectors")]
+    SectorOutOfRange { sector: usize, count: usize },
+
+    #[error("Page {page} cannot be erased, the largest page number is {max}")]
+    PageOutOfRange { page: u32, max: u32 },
+
+    #[error("{len} bytes at address 0x{address:08X} are out of range")]
+    AddressOutOfRange { address: u32, len: usize },
+
+    #[error("unknown device with product ID 0x{0:04X}, its flash layout must be given")]
+    UnknownDevice(u16),
+
+    #[error("option byte layout of product ID 0x{0:04X} is not known")]
+    UnknownOptionBytes(u16),
+
+    #[error("unknown option byte field {0:?}")]
+    UnknownOptionField(String),
+
+    #[error(
+        "value 0x{value:X} does not fit option byte field {name}, the largest value is 0x{max:X}"
+    )]
+    OptionFieldValue {
+        name: &'static str,
+        value: u32,
+        max: u32,
+    },
+
+    #[error("refusing to set option byte field {name} to 0x{value:X}, it cannot be undone")]
+    PermanentOptionValue { name: &'static str, value: u32 },
+
+    #[error("verification failed, memory differs at address 0x{address:08X}")]
+    VerifyMismatch { address: u32 },
+
+    /// An error with a message describing what was being done
+    #[error("{message}")]
+    Context {
+        message: String,
+        #[source]
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    /// The underlying error, with any context messages removed
+    ///
+    /// # Example
+    /// ```
+    /// # use stm32_an3155_rs::{BootloaderCommand, Error};
+    /// let error = Error::Context {
+    ///     message: "Failed to erase flash".into(),
+    ///     source: Box::new(Error::Nack(BootloaderCommand::Erase)),
+    /// };
+    /// assert!(matches!(error.root(), Error::Nack(BootloaderCommand::Erase)));
+    /// ```
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Context { source, .. } => source.root(),
+            error => error,
+        }
+    }
+
+    /// Whether the bootloader did not answer in time
+    pub fn is_timeout(&self) -> bool {
+        matches!(self.root(), Error::Io(e) if e.kind() == IoErrorKind::TimedOut)
+    }
+}
+
+/// Attach a context message to an error
+pub(crate) trait Context<T> {
+    fn context(self, message: &str) -> Result<T>;
+
+    fn with_context<F: FnOnce() -> String>(self, message: F) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for std::result::Result<T, E> {
+    fn context(self, message: &str) -> Result<T> {
+        self.with_context(|| message.to_owned())
+    }
+
+    fn with_context<F: FnOnce() -> String>(self, message: F) -> Result<T> {
+        self.map_err(|error| Error::Context {
+            message: message(),
+            source: Box::new(error.into()),
+        })
+    }
+}
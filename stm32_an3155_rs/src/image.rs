@@ -0,0 +1,151 @@
+use crate::Error;
+
+mod elf;
+mod ihex;
+mod srec;
+
+pub use elf::ELF_MAGIC;
+
+/// Contiguous block of firmware data
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    /// Address of the first byte
+    pub address: u32,
+    /// Bytes to write
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    /// Address one past the last byte
+    pub fn end(&self) -> u64 {
+        self.address as u64 + self.data.len() as u64
+    }
+}
+
+/// Firmware image made of non-overlapping segments
+///
+/// Segments are sorted by address and adjacent blocks of data are merged, so
+/// each segment can be written with consecutive write memory commands.
+///
+/// # Example
+/// ```
+/// # use stm32_an3155_rs::Image;
+/// let hex = ":020000040800F2\n\
+///            :0400000001020304F2\n\
+///            :0400040005060708DE\n\
+///            :00000001FF\n";
+/// let image = Image::from_ihex(hex).unwrap();
+/// assert_eq!(1, image.segments.len());
+/// assert_eq!(0x0800_0000, image.segments[0].address);
+/// assert_eq!(vec![1, 2, 3, 4, 5, 6, 7, 8], image.segments[0].data);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+    /// Entry point given by the image, if any
+    ///
+    /// This is usually the address of the reset handler, which Go cannot
+    /// start from; see [`vector_table`](Self::vector_table).
+    pub entry: Option<u32>,
+}
+
+impl Image {
+    /// Image holding a raw binary written at `address`
+    pub fn from_binary(address: u32, data: Vec<u8>) -> Self {
+        Self {
+            segments: vec![Segment { address, data }],
+            entry: None,
+        }
+    }
+
+    /// Build an image from blocks of data in any order
+    ///
+    /// Adjacent blocks are merged and overlapping blocks are rejected.
+    pub fn from_blocks(
+        blocks: impl IntoIterator<Item = Segment>,
+        entry: Option<u32>,
+    ) -> Result<Self, Error> {
+        let mut blocks: Vec<Segment> = blocks.into_iter().filter(|b| !b.data.is_empty()).collect();
+        blocks.sort_by_key(|b| b.address);
+
+        let mut segments: Vec<Segment> = Vec::with_capacity(blocks.len());
+        for block in blocks {
+            match segments.last_mut() {
+                Some(last) if last.end() == block.address as u64 => {
+                    last.data.extend_from_slice(&block.data)
+                }
+                Some(last) if last.end() > block.address as u64 => {
+                    return Err(Error::InvalidImage(format!(
+                        "data at address 0x{:08X} overlaps data at address 0x{:08X}",
+                        block.address, last.address
+                    )));
+                }
+                _ => segments.push(block),
+            }
+        }
+        Ok(Self { segments, entry })
+    }
+
+    /// Total number of data bytes in the image
+    pub fn size(&self) -> usize {
+        self.segments.iter().map(|s| s.data.len()).sum()
+    }
+
+    /// Whether the image holds no data
+    pub fn is_empty(&self) -> bool {
+        self.size() == 0
+    }
+
+    /// Address of the vector table, where Go starts the firmware
+    ///
+    /// Go loads the stack pointer and reset handler from the start of the
+    /// vector table.  This is the segment whose reset vector, the word at
+    /// offset 4, points to the entry point, or else the lowest segment.
+    ///
+    /// # Example
+    /// ```
+    /// # use stm32_an3155_rs::{Image, Segment};
+    /// let vectors = [0x2000_5000u32, 0x0800_0201]
+    ///     .iter()
+    ///     .flat_map(|word| word.to_le_bytes())
+    ///     .collect();
+    /// let image = Image::from_blocks(
+    ///     [
+    ///         Segment { address: 0x0800_0000, data: vec![0xFF; 8] },
+    ///         Segment { address: 0x0800_0100, data: vectors },
+    ///     ],
+    ///     Some(0x0800_0201),
+    /// )?;
+    /// assert_eq!(Some(0x0800_0100), image.vector_table());
+    /// # Ok::<(), stm32_an3155_rs::Error>(())
+    /// ```
+    pub fn vector_table(&self) -> Option<u32> {
+        let reset_vector = |segment: &&Segment| {
+            let word = segment.data.get(4..8)?;
+            Some(u32::from_le_bytes(word.try_into().ok()?))
+        };
+        self.entry
+            .and_then(|entry| {
+                self.segments
+                    .iter()
+                    .find(|segment| reset_vector(segment) == Some(entry))
+            })
+            .or(self.segments.first())
+            .map(|segment| segment.address)
+    }
+}
+
+/// Decode the hex digits of a text record
+fn decode_hex(hex: &str) -> Result<Vec<u8>, &'static str> {
+    if !hex.len().is_multiple_of(2) {
+        return Err("odd number of hex digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or("invalid hex digit")
+        })
+        .collect()
+}
@@ -0,0 +1,83 @@
+use super::{Image, Segment};
+use crate::Error;
+
+/// Magic bytes at the start of every ELF file
+pub const ELF_MAGIC: &[u8] = b"\x7FELF";
+
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ELF_HEADER_LEN: usize = 52;
+const PROGRAM_HEADER_LEN: usize = 32;
+const PT_LOAD: u32 = 1;
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidImage(format!("ELF: {reason}"))
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16, Error> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid("file is truncated"))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, Error> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| invalid("file is truncated"))
+}
+
+impl Image {
+    /// Parse a 32-bit little endian ELF file
+    ///
+    /// Every `PT_LOAD` segment is placed at its physical (load) address, so
+    /// initialized data is written where the startup code copies it from.
+    /// Only the bytes present in the file are used; the zero-initialized
+    /// remainder of a segment (`.bss` and other NOBITS sections) is skipped.
+    /// The ELF entry point becomes the image's entry point.
+    pub fn from_elf(bytes: &[u8]) -> Result<Self, Error> {
+        if !bytes.starts_with(ELF_MAGIC) {
+            return Err(invalid("missing ELF magic"));
+        }
+        if bytes.len() < ELF_HEADER_LEN {
+            return Err(invalid("file is truncated"));
+        }
+        if bytes[4] != ELFCLASS32 {
+            return Err(invalid("only 32-bit files are supported"));
+        }
+        if bytes[5] != ELFDATA2LSB {
+            return Err(invalid("only little endian files are supported"));
+        }
+
+        let entry = u32_at(bytes, 24)?;
+        let phoff = u32_at(bytes, 28)? as usize;
+        let phentsize = u16_at(bytes, 42)? as usize;
+        let phnum = u16_at(bytes, 44)? as usize;
+        if phnum > 0 && phentsize < PROGRAM_HEADER_LEN {
+            return Err(invalid("program header entries are too small"));
+        }
+
+        let mut blocks = Vec::with_capacity(phnum);
+        for index in 0..phnum {
+            let header = phoff + index * phentsize;
+            let p_type = u32_at(bytes, header)?;
+            let p_offset = u32_at(bytes, header + 4)? as usize;
+            let p_paddr = u32_at(bytes, header + 12)?;
+            let p_filesz = u32_at(bytes, header + 16)? as usize;
+            if p_type != PT_LOAD || p_filesz == 0 {
+                continue;
+            }
+
+            let data = bytes
+                .get(p_offset..p_offset + p_filesz)
+                .ok_or_else(|| invalid("segment data is outside of the file"))?;
+            blocks.push(Segment {
+                address: p_paddr,
+                data: data.to_vec(),
+            });
+        }
+
+        Self::from_blocks(blocks, Some(entry))
+    }
+}
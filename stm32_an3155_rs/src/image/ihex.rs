@@ -0,0 +1,140 @@
+use super::{decode_hex, Image, Segment};
+use crate::Error;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Number of data bytes per record written by [`Image::to_ihex`]
+const BYTES_PER_RECORD: usize = 16;
+
+/// Format a record, appending its byte count and checksum
+fn record(text: &mut String, offset: u16, record_type: u8, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(offset.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend_from_slice(data);
+    let checksum = bytes.iter().fold(0u8, |acc, b| acc.wrapping_sub(*b));
+    bytes.push(checksum);
+
+    text.push(':');
+    for byte in bytes {
+        text.push_str(&format!("{byte:02X}"));
+    }
+    text.push('\n');
+}
+
+fn invalid(line: usize, reason: &str) -> Error {
+    Error::InvalidImage(format!("Intel HEX line {line}: {reason}"))
+}
+
+impl Image {
+    /// Parse an Intel HEX file
+    ///
+    /// Supports data, end of file, extended segment and linear address, and
+    /// start segment and linear address records.  The start address, if
+    /// present, becomes the image's entry point.
+    pub fn from_ihex(text: &str) -> Result<Self, Error> {
+        let mut blocks = Vec::new();
+        let mut entry = None;
+        let mut base = 0u32;
+        let mut eof = false;
+
+        for (index, record) in text.lines().enumerate() {
+            let line = index + 1;
+            let record = record.trim();
+            if record.is_empty() {
+                continue;
+            }
+            if eof {
+                return Err(invalid(line, "data after end of file record"));
+            }
+            let hex = record
+                .strip_prefix(':')
+                .ok_or_else(|| invalid(line, "missing start code"))?;
+            let bytes = decode_hex(hex).map_err(|reason| invalid(line, reason))?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(invalid(line, "record length does not match byte count"));
+            }
+            if bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != 0 {
+                return Err(invalid(line, "invalid checksum"));
+            }
+
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let record_type = bytes[3];
+            let data = &bytes[4..bytes.len() - 1];
+            match (record_type, data.len()) {
+                (DATA, _) => blocks.push(Segment {
+                    address: base.wrapping_add(offset),
+                    data: data.to_vec(),
+                }),
+                (END_OF_FILE, 0) => eof = true,
+                (EXTENDED_SEGMENT_ADDRESS, 2) => {
+                    base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4
+                }
+                (EXTENDED_LINEAR_ADDRESS, 2) => {
+                    base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16
+                }
+                (START_SEGMENT_ADDRESS, 4) => {
+                    let cs = u16::from_be_bytes([data[0], data[1]]) as u32;
+                    let ip = u16::from_be_bytes([data[2], data[3]]) as u32;
+                    entry = Some((cs << 4) + ip);
+                }
+                (START_LINEAR_ADDRESS, 4) => {
+                    entry = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+                }
+                (
+                    END_OF_FILE
+                    | EXTENDED_SEGMENT_ADDRESS
+                    | EXTENDED_LINEAR_ADDRESS
+                    | START_SEGMENT_ADDRESS
+                    | START_LINEAR_ADDRESS,
+                    _,
+                ) => return Err(invalid(line, "wrong data length for record type")),
+                (t, _) => return Err(invalid(line, &format!("unknown record type 0x{t:02X}"))),
+            }
+        }
+
+        if !eof {
+            return Err(Error::InvalidImage(
+                "Intel HEX file has no end of file record".into(),
+            ));
+        }
+        Self::from_blocks(blocks, entry)
+    }
+
+    /// Format the image as an Intel HEX file
+    ///
+    /// Data is written in records of 16 bytes, with extended linear address
+    /// records whenever the upper 16 bits of the address change.  The entry
+    /// point, if any, is written as a start linear address record.
+    pub fn to_ihex(&self) -> String {
+        let mut text = String::new();
+        let mut upper = None;
+        for segment in &self.segments {
+            let mut address = segment.address;
+            let mut data = segment.data.as_slice();
+            while !data.is_empty() {
+                if upper != Some(address >> 16) {
+                    upper = Some(address >> 16);
+                    let base = (address >> 16) as u16;
+                    record(&mut text, 0, EXTENDED_LINEAR_ADDRESS, &base.to_be_bytes());
+                }
+                // Records don't cross a 64 KiB boundary
+                let remaining = 0x1_0000 - (address & 0xFFFF) as usize;
+                let len = data.len().min(BYTES_PER_RECORD).min(remaining);
+                record(&mut text, address as u16, DATA, &data[..len]);
+                data = &data[len..];
+                address = address.wrapping_add(len as u32);
+            }
+        }
+        if let Some(entry) = self.entry {
+            record(&mut text, 0, START_LINEAR_ADDRESS, &entry.to_be_bytes());
+        }
+        record(&mut text, 0, END_OF_FILE, &[]);
+        text
+    }
+}
@@ -0,0 +1,81 @@
+use super::{decode_hex, Image, Segment};
+use crate::Error;
+
+fn invalid(line: usize, reason: &str) -> Error {
+    Error::InvalidImage(format!("S-record line {line}: {reason}"))
+}
+
+/// Number of address bytes for a record type
+fn address_len(record_type: char) -> Option<usize> {
+    match record_type {
+        '0' | '1' | '5' | '9' => Some(2),
+        '2' | '6' | '8' => Some(3),
+        '3' | '7' => Some(4),
+        _ => None,
+    }
+}
+
+impl Image {
+    /// Parse a Motorola S-record file
+    ///
+    /// Supports S1, S2 and S3 data records and S7, S8 and S9 termination
+    /// records.  Header (S0) and record count (S5, S6) records are ignored.
+    /// A non-zero address in the termination record becomes the image's entry
+    /// point.
+    pub fn from_srec(text: &str) -> Result<Self, Error> {
+        let mut blocks = Vec::new();
+        let mut entry = None;
+        let mut terminated = false;
+
+        for (index, record) in text.lines().enumerate() {
+            let line = index + 1;
+            let record = record.trim();
+            if record.is_empty() {
+                continue;
+            }
+            if terminated {
+                return Err(invalid(line, "data after termination record"));
+            }
+            let mut chars = record
+                .strip_prefix('S')
+                .ok_or_else(|| invalid(line, "missing start code"))?
+                .chars();
+            let record_type = chars
+                .next()
+                .ok_or_else(|| invalid(line, "missing record type"))?;
+            let hex = chars.as_str();
+            let address_len = address_len(record_type)
+                .ok_or_else(|| invalid(line, &format!("unknown record type S{record_type}")))?;
+            let bytes = decode_hex(hex).map_err(|reason| invalid(line, reason))?;
+            if bytes.len() < address_len + 2 || bytes.len() != bytes[0] as usize + 1 {
+                return Err(invalid(line, "record length does not match byte count"));
+            }
+            if bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != 0xFF {
+                return Err(invalid(line, "invalid checksum"));
+            }
+
+            let address = bytes[1..=address_len]
+                .iter()
+                .fold(0u32, |acc, b| (acc << 8) | *b as u32);
+            let data = &bytes[address_len + 1..bytes.len() - 1];
+            match record_type {
+                '1' | '2' | '3' => blocks.push(Segment {
+                    address,
+                    data: data.to_vec(),
+                }),
+                '7' | '8' | '9' => {
+                    terminated = true;
+                    entry = Some(address).filter(|&a| a != 0);
+                }
+                _ => {}
+            }
+        }
+
+        if !terminated {
+            return Err(Error::InvalidImage(
+                "S-record file has no termination record".into(),
+            ));
+        }
+        Self::from_blocks(blocks, entry)
+    }
+}
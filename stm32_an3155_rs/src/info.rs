@@ -0,0 +1,52 @@
+use crate::{BootloaderCommand, Version};
+use std::convert::TryFrom;
+
+/// Bootloader details from the Get and GetVersion commands
+///
+/// # Example
+/// ```
+/// # use stm32_an3155_rs::{sim::Simulator, BootloaderCommand, Builder};
+/// let sim = Simulator::new().and_version(0x31).and_unknown_commands(&[0xB2]);
+/// let mut an3155 = Builder::with_transport(sim).initialize()?;
+///
+/// let info = an3155.get_info()?;
+/// assert_eq!((3, 1), info.version.value());
+/// assert_eq!([0x00, 0x00], info.option_bytes);
+/// assert!(info.supports(BootloaderCommand::ReadMemory));
+/// assert_eq!(vec![0xB2], info.unknown_opcodes());
+/// # Ok::<(), stm32_an3155_rs::Error>(())
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BootloaderInfo {
+    /// Protocol version
+    pub version: Version,
+    /// Option bytes sent after the version by GetVersion
+    pub option_bytes: [u8; 2],
+    /// Opcodes of every command the bootloader supports, in the order it
+    /// lists them, including opcodes this library does not know
+    pub opcodes: Vec<u8>,
+}
+
+impl BootloaderInfo {
+    /// Supported commands known to this library
+    pub fn commands(&self) -> Vec<BootloaderCommand> {
+        self.opcodes
+            .iter()
+            .filter_map(|&opcode| BootloaderCommand::try_from(opcode).ok())
+            .collect()
+    }
+
+    /// Supported opcodes that do not match any [`BootloaderCommand`]
+    pub fn unknown_opcodes(&self) -> Vec<u8> {
+        self.opcodes
+            .iter()
+            .copied()
+            .filter(|&opcode| BootloaderCommand::try_from(opcode).is_err())
+            .collect()
+    }
+
+    /// Whether the bootloader supports `command`
+    pub fn supports(&self, command: BootloaderCommand) -> bool {
+        self.opcodes.contains(&(command as u8))
+    }
+}
@@ -0,0 +1,166 @@
+use std::{borrow::Cow, ops::Range};
+
+/// Run of equally sized flash pages or sectors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sectors {
+    /// Size of each sector in bytes
+    pub size: u32,
+    /// Number of sectors
+    pub count: u32,
+}
+
+/// Organization of a device's flash memory
+///
+/// Flash is described as a base address followed by runs of equally sized
+/// sectors.  Parts with uniform pages have a single run, while e.g. the
+/// STM32F4 family mixes 16, 64 and 128 KiB sectors.  Sectors are numbered
+/// from zero in address order, which matches the page and sector numbers
+/// used by the erase commands.
+///
+/// The write protect command uses its own sector numbers.  On most families
+/// they match the erase sectors, while e.g. STM32F1 parts protect groups of
+/// pages; see [`and_wrp_sectors`](Self::and_wrp_sectors).
+///
+/// # Example
+/// ```
+/// # use stm32_an3155_rs::{FlashLayout, Sectors};
+/// const F4: &[Sectors] = &[
+///     Sectors { size: 16 * 1024, count: 4 },
+///     Sectors { size: 64 * 1024, count: 1 },
+///     Sectors { size: 128 * 1024, count: 7 },
+/// ];
+/// let layout = FlashLayout::new(0x0800_0000, F4);
+///
+/// assert_eq!(12, layout.sector_count());
+/// assert_eq!(1024 * 1024, layout.size());
+/// assert_eq!(Some(4), layout.sector_at(0x0801_0000));
+/// assert_eq!(Some(0..5), layout.sectors_in_range(0x0800_0000, 0x1_0001));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlashLayout {
+    base: u32,
+    sectors: Cow<'static, [Sectors]>,
+    /// Pages covered by each write protection sector
+    wrp_pages: u32,
+    /// Number of write protection sectors, if limited
+    wrp_count: Option<u32>,
+}
+
+impl FlashLayout {
+    pub const fn new(base: u32, sectors: &'static [Sectors]) -> Self {
+        Self {
+            base,
+            sectors: Cow::Borrowed(sectors),
+            wrp_pages: 1,
+            wrp_count: None,
+        }
+    }
+
+    /// Flash made of `count` pages of `size` bytes
+    pub fn uniform(base: u32, size: u32, count: u32) -> Self {
+        Self {
+            base,
+            sectors: Cow::Owned(vec![Sectors { size, count }]),
+            wrp_pages: 1,
+            wrp_count: None,
+        }
+    }
+
+    /// Write protection sectors of `pages` pages each, at most `count` of
+    /// them.  The last sector covers all remaining pages.
+    ///
+    /// # Example
+    /// ```
+    /// # use stm32_an3155_rs::FlashLayout;
+    /// let layout = FlashLayout::uniform(0x0800_0000, 2048, 256).and_wrp_sectors(2, 32);
+    ///
+    /// assert_eq!(32, layout.wrp_sector_count());
+    /// assert_eq!(Some(2..4), layout.wrp_sector_pages(1));
+    /// assert_eq!(Some(62..256), layout.wrp_sector_pages(31));
+    /// assert_eq!(None, layout.wrp_sector_pages(32));
+    /// ```
+    pub const fn and_wrp_sectors(mut self, pages: u32, count: u32) -> Self {
+        self.wrp_pages = pages;
+        self.wrp_count = Some(count);
+        self
+    }
+
+    /// Start address of flash memory
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// Total size of flash memory in bytes
+    pub fn size(&self) -> u32 {
+        self.sectors.iter().map(|s| s.size * s.count).sum()
+    }
+
+    /// Number of pages or sectors
+    pub fn sector_count(&self) -> usize {
+        self.sectors.iter().map(|s| s.count as usize).sum()
+    }
+
+    /// Address range of every sector, in order
+    pub fn iter(&self) -> impl Iterator<Item = Range<u32>> + '_ {
+        let mut start = self.base;
+        self.sectors
+            .iter()
+            .flat_map(|s| std::iter::repeat_n(s.size, s.count as usize))
+            .map(move |size| {
+                let sector = start..start + size;
+                start += size;
+                sector
+            })
+    }
+
+    /// Address range of a sector
+    pub fn sector(&self, index: usize) -> Option<Range<u32>> {
+        self.iter().nth(index)
+    }
+
+    /// Number of sectors the write protect command accepts
+    pub fn wrp_sector_count(&self) -> usize {
+        let count = self.sector_count().div_ceil(self.wrp_pages as usize);
+        match self.wrp_count {
+            Some(max) => count.min(max as usize),
+            None => count,
+        }
+    }
+
+    /// Pages or sectors covered by a write protection sector
+    pub fn wrp_sector_pages(&self, index: usize) -> Option<Range<usize>> {
+        let count = self.wrp_sector_count();
+        if index >= count {
+            return None;
+        }
+        let start = index * self.wrp_pages as usize;
+        let end = match index + 1 == count {
+            true => self.sector_count(),
+            false => start + self.wrp_pages as usize,
+        };
+        Some(start..end)
+    }
+
+    /// Index of the sector containing `address`
+    pub fn sector_at(&self, address: u32) -> Option<usize> {
+        self.iter().position(|sector| sector.contains(&address))
+    }
+
+    /// Whether `len` bytes starting at `address` are all in flash
+    pub fn contains(&self, address: u32, len: u32) -> bool {
+        let end = self.base as u64 + self.size() as u64;
+        address >= self.base && address as u64 + len as u64 <= end
+    }
+
+    /// Indices of the sectors touched by `len` bytes starting at `address`
+    ///
+    /// Returns `None` if any part of the range is outside of flash.
+    pub fn sectors_in_range(&self, address: u32, len: u32) -> Option<Range<usize>> {
+        if len == 0 || !self.contains(address, len) {
+            return None;
+        }
+        let first = self.sector_at(address)?;
+        let last = self.sector_at(address + (len - 1))?;
+        Some(first..last + 1)
+    }
+}
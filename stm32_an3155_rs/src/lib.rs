@@ -1,22 +1,75 @@
-use anyhow::Context;
-use log::{debug, error, info, trace, warn};
-use thiserror::Error as ThisError;
+use error::Context;
+use log::{debug, info, trace, warn};
 
 use std::{
     convert::TryFrom,
     io::{Error as IoError, ErrorKind as IoErrorKind},
+    thread,
     time::Duration,
 };
 
+mod baud;
+mod control;
+mod crc;
+mod device;
+mod error;
+mod image;
+mod info;
+mod layout;
+mod ops;
+mod option_bytes;
+mod progress;
+mod retry;
+pub mod sim;
+mod transport;
+
+pub use baud::{BaudProbe, StressCheck, DEFAULT_PROBE_BAUD_RATES, DEFAULT_STRESS_LEN};
+pub use control::{
+    BootPins, ControlLine, ControlSequence, ControlStep, DEFAULT_BOOT_DELAY, DEFAULT_RESET_PULSE,
+};
+pub use crc::{crc32, crc32_with_polynomial, CRC32_INITIAL, CRC32_POLYNOMIAL};
+pub use device::{Device, DEVICES};
+pub use error::{Error, Result};
+pub use image::{Image, Segment, ELF_MAGIC};
+pub use info::BootloaderInfo;
+pub use layout::{FlashLayout, Sectors};
+pub use ops::{EraseStrategy, FlashOptions, Mismatch, VerifyMode};
+pub use option_bytes::{OptionBytes, OptionBytesLayout, OptionField};
+pub use progress::{Phase, Progress, ProgressObserver};
+pub use retry::{is_retryable, RetryPolicy, DEFAULT_RETRY_BACKOFF};
+pub use transport::Transport;
+
 /// Baudrate sync byte used during initialization
 const SYNC_BYTE: u8 = 0x7F;
 
+/// Byte sent to complete a frame the bootloader is still waiting for
+const RESYNC_BYTE: u8 = 0xFF;
+
+/// Maximum number of bytes sent while getting back in step with the bootloader
+const MAX_RESYNC_BYTES: usize = 8;
+
 /// Default baud rate
 pub const DEFAULT_BAUDRATE: u32 = 57_600;
 
+/// Default time to wait for the bootloader to restart after a system reset
+pub const DEFAULT_RESET_DELAY: Duration = Duration::from_millis(100);
+
+/// Default number of sync bytes sent before giving up on the bootloader
+pub const DEFAULT_SYNC_ATTEMPTS: u32 = 3;
+
+/// Fewest sync bytes sent before giving up on the bootloader.  A bootloader
+/// that is already synchronized only answers the second one
+pub const MIN_SYNC_ATTEMPTS: u32 = 2;
+
+/// Default delay between sync attempts
+pub const DEFAULT_SYNC_DELAY: Duration = Duration::from_millis(50);
+
 /// Maximum number of pages that can be erased in a single standard erase command
 pub const MAX_ERASE_PAGE_COUNT: usize = u8::MAX as usize;
 
+/// Maximum number of sectors that can be protected in a single write protect command
+pub const MAX_WRITE_PROTECT_SECTOR_COUNT: usize = u8::MAX as usize + 1;
+
 /// Maximum number of bytes that can be written in a single write memory command
 pub const MAX_WRITE_BYTES_COUNT: usize = u8::MAX as usize + 1;
 
@@ -26,6 +79,15 @@ pub const MAX_READ_BYTES_COUNT: usize = u8::MAX as usize + 1;
 /// Default page size in bytes
 pub const DEFAULT_PAGE_SIZE: usize = 128;
 
+/// Maximum number of data bytes in a single special command
+pub const MAX_SPECIAL_DATA_COUNT: usize = 128;
+
+/// Maximum number of file data bytes in a single extended special command
+pub const MAX_EXTENDED_SPECIAL_DATA_COUNT: usize = 1024;
+
+/// Value of erased flash memory
+pub const ERASED_BYTE: u8 = 0xFF;
+
 /// Default starting target address
 pub const DEFAULT_START_ADDRESS: u32 = 0x0800_0000;
 
@@ -113,6 +175,7 @@ impl TryFrom<u8> for Response {
 ///
 /// Each chip's bootloader will support either the Erase command or
 /// the ExtendedErase command.  The commands are mutually exclusive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum EraseCommand {
     /// Normal erase command
     Erase,
@@ -121,6 +184,7 @@ pub enum EraseCommand {
 }
 
 /// Extended Erase global erase target
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BankErase {
     /// Erase all banks
     Global,
@@ -130,38 +194,27 @@ pub enum BankErase {
     Bank2,
 }
 
-#[derive(ThisError, Debug)]
-pub enum Error {
-    #[error("invalid response from bootloader: 0x{0:02X}")]
-    InvalidResponse(u8),
-
-    #[error("received a NACK from bootloader")]
-    Nack,
-
-    #[error("invalid bootloader command: 0x{0:02X}")]
-    InvalidBootloaderCommand(u8),
-
-    #[error("unsupported operation")]
-    Unsupported,
-
-    #[error("Erase command supports only up to 254 pages.  Provided {0}")]
-    ErasePageCount(usize),
-
-    #[error("Write command supports only up to 256 bytes.  Provided {0}")]
-    WriteBytesCount(usize),
+/// Response to a Special command
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpecialResponse {
+    /// Data returned by the operation
+    pub data: Vec<u8>,
+    /// Status returned by the operation
+    pub status: Vec<u8>,
 }
 
 /// Bootloader version
 ///
 /// # Example
 /// ```
-/// # use stm32_an3155::Version;
+/// # use stm32_an3155_rs::Version;
 /// let ver = Version::from(0x10);
 ///
 /// assert_eq!(1, ver.major());
 /// assert_eq!(0, ver.minor());
 /// assert_eq!((1, 0), ver.value());
 /// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Version(u8);
 
 impl Version {
@@ -184,18 +237,80 @@ impl From<u8> for Version {
     }
 }
 
-pub struct Builder<'a> {
+/// Function used by [`Builder`] to open the transport with the configured
+/// baud rate and timeout
+type OpenFn<'a, T> = Box<dyn FnOnce(Option<u32>, Option<Duration>) -> Result<T> + 'a>;
+
+pub struct Builder<'a, T = Box<dyn serialport::SerialPort>> {
     baud_rate: Option<u32>,
     timeout: Option<Duration>,
-    path: &'a str,
+    reset_delay: Option<Duration>,
+    sync_attempts: Option<u32>,
+    sync_delay: Option<Duration>,
+    retry_policy: Option<RetryPolicy>,
+    entry_sequence: Option<ControlSequence>,
+    exit_sequence: Option<ControlSequence>,
+    baud_probe: Option<BaudProbe>,
+    open: OpenFn<'a, T>,
 }
 
 impl<'a> Builder<'a> {
     pub fn with_path(path: &'a str) -> Self {
         Self {
-            path,
             baud_rate: None,
             timeout: None,
+            reset_delay: None,
+            sync_attempts: None,
+            sync_delay: None,
+            retry_policy: None,
+            entry_sequence: None,
+            exit_sequence: None,
+            baud_probe: None,
+            open: Box::new(move |baud_rate, timeout| {
+                let baud_rate = baud_rate.unwrap_or(DEFAULT_BAUDRATE);
+                info!("opening serial port: {path} {baud_rate} 8E1");
+                serialport::new(path, baud_rate)
+                    .parity(serialport::Parity::Even)
+                    .stop_bits(serialport::StopBits::One)
+                    .data_bits(serialport::DataBits::Eight)
+                    .timeout(timeout.unwrap_or(Duration::from_secs(1)))
+                    .open()
+                    .context("Failed to open serialport device")
+            }),
+        }
+    }
+}
+
+impl<'a, T: Transport + 'a> Builder<'a, T> {
+    /// Use an already opened transport
+    ///
+    /// The baud rate and timeout are only applied to the transport if
+    /// they are set on the builder.
+    pub fn with_transport(transport: T) -> Self {
+        Self {
+            baud_rate: None,
+            timeout: None,
+            reset_delay: None,
+            sync_attempts: None,
+            sync_delay: None,
+            retry_policy: None,
+            entry_sequence: None,
+            exit_sequence: None,
+            baud_probe: None,
+            open: Box::new(move |baud_rate, timeout| {
+                let mut transport = transport;
+                if let Some(baud_rate) = baud_rate {
+                    transport
+                        .set_baud(baud_rate)
+                        .context("Failed to set transport baud rate")?;
+                }
+                if let Some(timeout) = timeout {
+                    transport
+                        .set_timeout(timeout)
+                        .context("Failed to set transport timeout")?;
+                }
+                Ok(transport)
+            }),
         }
     }
 
@@ -209,17 +324,68 @@ impl<'a> Builder<'a> {
         self
     }
 
-    fn build_serialport(self) -> anyhow::Result<Box<dyn serialport::SerialPort>> {
-        let path = self.path;
-        let baud_rate = self.baud_rate.unwrap_or(DEFAULT_BAUDRATE);
-        info!("opening serial port: {path} {baud_rate} 8E1");
-        serialport::new(path, baud_rate)
-            .parity(serialport::Parity::Even)
-            .stop_bits(serialport::StopBits::One)
-            .data_bits(serialport::DataBits::Eight)
-            .timeout(self.timeout.unwrap_or(Duration::from_secs(1)))
-            .open()
-            .context("Failed to open serialport device")
+    /// Time to wait for the bootloader to restart after commands that reset the chip
+    pub fn and_reset_delay(mut self, reset_delay: Duration) -> Self {
+        self.reset_delay.replace(reset_delay);
+        self
+    }
+
+    /// Number of sync bytes to send, `delay` apart, before reporting that no
+    /// bootloader was detected.  At least [`MIN_SYNC_ATTEMPTS`] are sent
+    pub fn and_sync_attempts(mut self, attempts: u32, delay: Duration) -> Self {
+        self.sync_attempts.replace(attempts.max(MIN_SYNC_ATTEMPTS));
+        self.sync_delay.replace(delay);
+        self
+    }
+
+    /// How failed commands are retried.  By default they are not
+    pub fn and_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy.replace(retry_policy);
+        self
+    }
+
+    /// Drive the control lines through `sequence` before synchronizing
+    pub fn and_entry_sequence(mut self, sequence: ControlSequence) -> Self {
+        self.entry_sequence.replace(sequence);
+        self
+    }
+
+    /// Control line sequence run by [`AN3155::exit_bootloader`]
+    pub fn and_exit_sequence(mut self, sequence: ControlSequence) -> Self {
+        self.exit_sequence.replace(sequence);
+        self
+    }
+
+    /// Reset into the bootloader, and back into the application on exit,
+    /// with the reset and BOOT0 pins wired to control lines
+    pub fn and_boot_pins(self, pins: BootPins) -> Self {
+        self.and_entry_sequence(pins.entry())
+            .and_exit_sequence(pins.exit())
+    }
+
+    /// Probe for the fastest working baud rate when initializing, instead of
+    /// using the configured one
+    pub fn and_baud_probe(mut self, probe: BaudProbe) -> Self {
+        self.baud_probe.replace(probe);
+        self
+    }
+
+    fn build(self) -> Result<AN3155<T>> {
+        let reset_delay = self.reset_delay.unwrap_or(DEFAULT_RESET_DELAY);
+        let sync_attempts = self.sync_attempts.unwrap_or(DEFAULT_SYNC_ATTEMPTS);
+        let sync_delay = self.sync_delay.unwrap_or(DEFAULT_SYNC_DELAY);
+        let retry_policy = self.retry_policy.unwrap_or_default();
+        let serial = (self.open)(self.baud_rate, self.timeout)?;
+        Ok(AN3155 {
+            serial,
+            reset_delay,
+            sync_attempts,
+            sync_delay,
+            retry_policy,
+            exit_sequence: self.exit_sequence,
+            baud_rate: self.baud_rate,
+            info: None,
+        })
     }
 
     /// Skip bootloader comms initialization
@@ -227,36 +393,97 @@ impl<'a> Builder<'a> {
     /// This can be useful if you've already communicated with
     /// the bootloader and need to send new commands.  To be
     /// successful you must use the same baud rate as the
-    /// original session
-    pub fn skip_initialization(self) -> anyhow::Result<AN3155> {
-        let serial = self.build_serialport()?;
-        Ok(AN3155 { serial })
+    /// original session.  The entry sequence is not run
+    pub fn skip_initialization(self) -> Result<AN3155<T>> {
+        self.build()
     }
 
     /// Initialize comms with the bootloader
-    pub fn initialize(self) -> anyhow::Result<AN3155> {
-        let mut serial = self.build_serialport()?;
-
-        info!("writing baudrate sync byte");
-        serial
-            .write(&[SYNC_BYTE][..])
-            .context("Failed to send baudrate sync byte")?;
-        let mut buf = [0u8];
-        info!("waiting for bootloader response");
-        serial
-            .read(&mut buf[..])
-            .context("Failed to read response from bootloader")?;
-
-        Ok(AN3155 { serial })
+    ///
+    /// Runs the entry sequence, if any, then sends sync bytes until the
+    /// bootloader answers, failing with [`Error::NoBootloader`] if it never
+    /// does.  With a [`BaudProbe`] this is repeated at each of its rates.
+    pub fn initialize(mut self) -> Result<AN3155<T>> {
+        let entry_sequence = self.entry_sequence.take();
+        let baud_probe = self.baud_probe.take();
+        let mut an3155 = self.build()?;
+
+        match baud_probe {
+            Some(probe) => {
+                an3155.probe_baud_rate(&probe, entry_sequence.as_ref())?;
+            }
+            None => {
+                an3155.enter_bootloader(entry_sequence.as_ref())?;
+                an3155.sync()?;
+            }
+        }
+        Ok(an3155)
     }
 }
 
-pub struct AN3155 {
-    serial: Box<dyn serialport::SerialPort>,
+pub struct AN3155<T = Box<dyn serialport::SerialPort>> {
+    serial: T,
+    reset_delay: Duration,
+    sync_attempts: u32,
+    sync_delay: Duration,
+    retry_policy: RetryPolicy,
+    exit_sequence: Option<ControlSequence>,
+    baud_rate: Option<u32>,
+    /// Cached answers to Get and GetVersion
+    info: Option<BootloaderInfo>,
 }
 
-impl AN3155 {
-    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<usize> {
+impl<T: Transport> AN3155<T> {
+    /// Get a reference to the underlying transport
+    pub fn transport(&self) -> &T {
+        &self.serial
+    }
+
+    /// Get a mutable reference to the underlying transport
+    ///
+    /// Reading or writing directly may leave the bootloader out of step
+    /// with this session.
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.serial
+    }
+
+    /// Consume the session and return the underlying transport
+    pub fn into_transport(self) -> T {
+        self.serial
+    }
+
+    /// Baud rate of the link, if it was set by the builder or found by probing
+    pub fn baud_rate(&self) -> Option<u32> {
+        self.baud_rate
+    }
+
+    /// Run the control line sequence that resets the chip into the bootloader
+    fn enter_bootloader(&mut self, sequence: Option<&ControlSequence>) -> Result<()> {
+        if let Some(sequence) = sequence {
+            info!("entering bootloader with control lines");
+            sequence
+                .run(&mut self.serial)
+                .context("Failed to drive serial control lines")?;
+            // The chip was reset and may come up in a different bootloader
+            self.info = None;
+        }
+        Ok(())
+    }
+
+    /// Run the exit sequence given to the builder, e.g. to reset into the
+    /// application.  Does nothing if there is none
+    pub fn exit_bootloader(&mut self) -> Result<()> {
+        if let Some(sequence) = &self.exit_sequence {
+            info!("leaving bootloader with control lines");
+            sequence
+                .run(&mut self.serial)
+                .context("Failed to drive serial control lines")?;
+            self.info = None;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<usize> {
         debug!("sending {} bytes: {:02X?}", bytes.len(), bytes);
         self.serial
             .write(bytes)
@@ -264,7 +491,15 @@ impl AN3155 {
     }
 
     /// Write a bootloader command and wait for a response
-    fn write_command(&mut self, command: BootloaderCommand) -> anyhow::Result<()> {
+    ///
+    /// Commands the bootloader does not advertise fail without being sent.
+    /// Get, GetVersion and GetId are supported by every bootloader.
+    fn write_command(&mut self, command: BootloaderCommand) -> Result<()> {
+        use BootloaderCommand::{Get, GetId, GetVersion};
+        if !matches!(command, Get | GetVersion | GetId) && !self.supports(command)? {
+            return Err(Error::Unsupported(command));
+        }
+
         let buf = [command as u8, !(command as u8)];
         debug!("sending command {:?}: {:02X?}", command, &buf[..]);
         let n = self.write(&buf[..]).context("Failed to write command")?;
@@ -272,10 +507,10 @@ impl AN3155 {
             return Err(IoError::from(IoErrorKind::WriteZero).into());
         }
 
-        self.read_ack()
+        self.read_ack(command)
     }
 
-    fn write_with_checksum(&mut self, bytes: &[u8]) -> anyhow::Result<usize> {
+    fn write_with_checksum(&mut self, bytes: &[u8]) -> Result<usize> {
         let chksum = bytes.iter().fold(0u8, |acc, b| acc ^ *b);
         let n = self.write(bytes)?;
         debug!("sending checksum value: {:02X}", chksum);
@@ -286,30 +521,23 @@ impl AN3155 {
         Ok(n + 1)
     }
 
-    #[cfg(never)]
-    fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
-        let n = self
-            .serial
-            .read(buf)
-            .context("Failed to read from serialport")?;
-        debug! {"read {} bytes: {:02X?}", n, &buf[..n]};
-        Ok(n)
-    }
-
-    fn read_exact(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
         debug!("reading exactly {} bytes", buf.len());
-        self.serial.read_exact(buf)?;
+        self.serial
+            .read_exact(buf)
+            .context("Failed to read from serial port")?;
         debug! {"read {} bytes: {:02X?}", buf.len(), &buf};
         Ok(())
     }
 
-    fn read_byte(&mut self) -> anyhow::Result<u8> {
+    fn read_byte(&mut self) -> Result<u8> {
         let mut byte = [0u8];
-        let _ = self.read_exact(&mut byte[..])?;
+        self.read_exact(&mut byte[..])?;
         Ok(byte[0])
     }
 
-    fn read_ack(&mut self) -> anyhow::Result<()> {
+    /// Read the response to a step of `command`
+    fn read_ack(&mut self, command: BootloaderCommand) -> Result<()> {
         debug!("reading bootloader response");
         let byte = self.read_byte()?;
         match Response::try_from(byte).context("Failed to read valid response from bootloader")? {
@@ -318,14 +546,115 @@ impl AN3155 {
                 Ok(())
             }
             Response::Nack => {
-                warn!("received NACK");
-                Err(Error::Nack.into())
+                warn!("received NACK for {command:?} command");
+                Err(Error::Nack(command))
+            }
+        }
+    }
+
+    /// Wait for the bootloader to restart after a system reset and sync with it again
+    fn resync_after_reset(&mut self) -> Result<()> {
+        info!("waiting {:?} for bootloader to restart", self.reset_delay);
+        thread::sleep(self.reset_delay);
+
+        // The restarted bootloader may answer Get and GetVersion differently
+        self.info = None;
+        self.sync()
+            .context("Bootloader did not respond after reset")
+    }
+
+    /// Send sync bytes until the bootloader answers
+    ///
+    /// A NACK is accepted too: the bootloader was already synchronized and
+    /// took the sync byte as the start of a command.  In that case the first
+    /// sync byte goes unanswered and the next one completes an invalid
+    /// command, so at least two attempts are needed to detect it.
+    fn sync(&mut self) -> Result<()> {
+        let attempts = self.sync_attempts;
+        for attempt in 1..=attempts {
+            if attempt > 1 {
+                thread::sleep(self.sync_delay);
+            }
+            self.serial
+                .discard_input()
+                .context("Failed to discard stale input")?;
+            info!("writing baudrate sync byte (attempt {attempt} of {attempts})");
+            self.write(&[SYNC_BYTE][..])
+                .context("Failed to send baudrate sync byte")?;
+            self.serial.flush()?;
+            match self.read_byte().map(Response::try_from) {
+                Ok(Ok(Response::Ack)) => {
+                    info!("bootloader synchronized");
+                    return Ok(());
+                }
+                Ok(Ok(Response::Nack)) => {
+                    info!("bootloader was already synchronized");
+                    return Ok(());
+                }
+                Ok(Err(error)) => warn!("unexpected answer to sync byte, {error}"),
+                Err(error) if error.is_timeout() => warn!("no response to sync byte"),
+                Err(error) => return Err(error),
             }
         }
+        Err(Error::NoBootloader { attempts })
+    }
+
+    /// Run `op` until it succeeds, following the retry policy
+    fn retry<R>(&mut self, mut op: impl FnMut(&mut Self) -> Result<R>) -> Result<R> {
+        let policy = self.retry_policy;
+        let mut attempt = 1;
+        loop {
+            let error = match op(self) {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+            if attempt >= policy.max_attempts || !(policy.is_retryable)(&error) {
+                return Err(error);
+            }
+            attempt += 1;
+            warn! {"{error}, retrying (attempt {attempt} of {})", policy.max_attempts};
+            thread::sleep(policy.delay(attempt));
+            // A NACK returns the bootloader to waiting for a command, any
+            // other failure may have left it part way through a frame
+            match error.root() {
+                Error::Nack(_) => self.serial.discard_input()?,
+                _ => self
+                    .resync()
+                    .context("Failed to get back in step with the bootloader")?,
+            }
+        }
+    }
+
+    /// Get back in step with a bootloader that may be waiting for the rest of a frame
+    ///
+    /// Filler bytes are sent one at a time until the bootloader answers,
+    /// which shows it has rejected the frame and is waiting for a command.
+    fn resync(&mut self) -> Result<()> {
+        info!("resynchronizing with bootloader");
+        self.serial.discard_input()?;
+        for _ in 0..MAX_RESYNC_BYTES {
+            self.write(&[RESYNC_BYTE][..])?;
+            self.serial.flush()?;
+            match self.read_byte() {
+                Ok(byte) => {
+                    debug! {"bootloader answered 0x{byte:02X}, back in step"};
+                    self.serial.discard_input()?;
+                    return Ok(());
+                }
+                Err(error) if error.is_timeout() => continue,
+                Err(error) => return Err(error),
+            }
+        }
+        Err(IoError::from(IoErrorKind::TimedOut)).context("Bootloader did not answer filler bytes")
     }
 
     /// Get the bootloader version
-    pub fn get_version(&mut self) -> anyhow::Result<Version> {
+    pub fn get_version(&mut self) -> Result<Version> {
+        let (version, _) = self.retry(Self::get_version_once)?;
+        Ok(version)
+    }
+
+    fn get_version_once(&mut self) -> Result<(Version, [u8; 2])> {
         info!("getting bootloader version");
         self.write_command(BootloaderCommand::GetVersion)
             .context("Failed to send GetVersion command")?;
@@ -334,16 +663,40 @@ impl AN3155 {
             .read_byte()
             .context("Failed to read protocol version byte")?;
 
-        info!("reading capatability bytes");
-        let mut buf = [0u8, 0u8];
-        self.read_exact(&mut buf)
-            .context("Failed to read compatability bytes")?;
-        self.read_ack()?;
-        Ok(Version::from(byte))
+        info!("reading option bytes");
+        let mut option_bytes = [0u8, 0u8];
+        self.read_exact(&mut option_bytes)
+            .context("Failed to read option bytes")?;
+        self.read_ack(BootloaderCommand::GetVersion)?;
+        Ok((Version::from(byte), option_bytes))
+    }
+
+    /// Get the bootloader version, option bytes and supported commands
+    ///
+    /// The chip is only queried the first time, later calls return the
+    /// cached answer until the chip is reset.
+    pub fn get_info(&mut self) -> Result<&BootloaderInfo> {
+        let info = match self.info.take() {
+            Some(info) => info,
+            None => {
+                let (version, opcodes) = self.retry(Self::get_once)?;
+                let (_, option_bytes) = self.retry(Self::get_version_once)?;
+                BootloaderInfo {
+                    version,
+                    option_bytes,
+                    opcodes,
+                }
+            }
+        };
+        Ok(self.info.insert(info))
     }
 
     /// Get product ID
-    pub fn get_id(&mut self) -> anyhow::Result<u16> {
+    pub fn get_id(&mut self) -> Result<u16> {
+        self.retry(Self::get_id_once)
+    }
+
+    fn get_id_once(&mut self) -> Result<u16> {
         info!("getting product id");
         self.write_command(BootloaderCommand::GetId)
             .context("Failed to send GetId command")?;
@@ -351,44 +704,61 @@ impl AN3155 {
         let n = self.read_byte()? as usize;
         // n should be 1, we expect to read two bytes here
         if n != 1 {
-            return Err(anyhow::Error::from(Error::InvalidResponse(n as u8))
-                .context("Expected two bytes for product ID"));
+            return Err(Error::ResponseLength {
+                len: n + 1,
+                expected: 2,
+            })
+            .context("Expected two bytes for product ID");
         }
 
-        let mut buf = Vec::with_capacity(2);
-        buf.resize(2, 0);
+        let mut buf = [0u8; 2];
 
         info!("receiving PID");
         self.read_exact(&mut buf)?;
-        Ok(u16::from_be_bytes(buf[0..2].try_into().unwrap()))
+        self.read_ack(BootloaderCommand::GetId)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    /// Get product ID and look it up in the built-in device table
+    pub fn get_device(&mut self) -> Result<Option<&'static Device>> {
+        let pid = self.get_id()?;
+        let device = Device::from_pid(pid);
+        match device {
+            Some(device) => info! {"detected device: {}", device.name},
+            None => warn! {"unknown product ID: 0x{:04X}", pid},
+        }
+        Ok(device)
+    }
+
+    /// Whether the bootloader advertises `command`
+    pub fn supports(&mut self, command: BootloaderCommand) -> Result<bool> {
+        Ok(self.get_info()?.supports(command))
+    }
+
+    /// Get the bootloader commands known to this library
+    ///
+    /// Uses the cached [`BootloaderInfo`], see [`AN3155::get_info`] for the
+    /// opcodes of unknown commands.
+    pub fn get_commands(&mut self) -> Result<Vec<BootloaderCommand>> {
+        Ok(self.get_info()?.commands())
     }
 
-    /// Get the bootloader commands
-    pub fn get_commands(&mut self) -> anyhow::Result<Vec<BootloaderCommand>> {
+    /// Send the Get command, returning the version and supported opcodes
+    fn get_once(&mut self) -> Result<(Version, Vec<u8>)> {
         info!("getting bootloader command set");
         self.write_command(BootloaderCommand::Get)
             .context("Failed to send Get command")?;
 
-        let n = self
-            .read_byte()
-            .context("Failed to read protocol version byte")? as usize;
+        let n = self.read_byte().context("Failed to read number of bytes")? as usize;
 
-        let mut buf = Vec::with_capacity(n as usize);
-        buf.resize(n + 1, 0);
+        let mut buf = vec![0u8; n + 1];
         self.read_exact(&mut buf)
             .context("Failed to read bootloader command list")?;
-        self.read_ack()?;
-        let mut commands: Vec<BootloaderCommand> = Vec::with_capacity(buf.len() - 1);
-        for b in buf.iter().skip(1) {
-            commands.push(
-                BootloaderCommand::try_from(*b)
-                    .context("Bootloader returned an unknown command value")?,
-            );
-        }
-        Ok(commands)
+        self.read_ack(BootloaderCommand::Get)?;
+        Ok((Version::from(buf[0]), buf.split_off(1)))
     }
 
-    pub fn get_erase_command(&mut self) -> anyhow::Result<EraseCommand> {
+    pub fn get_erase_command(&mut self) -> Result<EraseCommand> {
         let commands = self
             .get_commands()
             .context("Failed to get bootloader command list")?;
@@ -398,12 +768,12 @@ impl AN3155 {
         } else if commands.contains(&BootloaderCommand::ExtendedErase) {
             Ok(EraseCommand::ExtendedErase)
         } else {
-            Err(Error::Unsupported.into())
+            Err(Error::Unsupported(BootloaderCommand::Erase))
         }
     }
 
     /// Standard erase command
-    pub fn standard_erase(&mut self, pages: &[u8]) -> anyhow::Result<()> {
+    pub fn standard_erase(&mut self, pages: &[u8]) -> Result<()> {
         info! {"erasing {} pages with standard erase command", pages.len()};
         if pages.is_empty() {
             warn! {"no pages to erase, doing nothing"};
@@ -411,7 +781,7 @@ impl AN3155 {
         }
 
         if pages.len() > MAX_ERASE_PAGE_COUNT {
-            return Err(Error::ErasePageCount(pages.len()).into());
+            return Err(Error::ErasePageCount(pages.len()));
         }
 
         let n = (pages.len() - 1) as u8;
@@ -426,20 +796,20 @@ impl AN3155 {
         self.write(&[checksum][..])?;
         self.serial.flush()?;
 
-        self.read_ack()
+        self.read_ack(BootloaderCommand::Erase)
     }
 
     /// Global erase with standard erase command
-    pub fn standard_global_erase(&mut self) -> anyhow::Result<()> {
+    pub fn standard_global_erase(&mut self) -> Result<()> {
         info! {"erasing all pages with standard erase command"}
         self.write_command(BootloaderCommand::Erase)?;
         self.write(&[0xFF, 0x00][..])?;
         self.serial.flush()?;
-        self.read_ack()
+        self.read_ack(BootloaderCommand::Erase)
     }
 
     /// Extended erase command
-    pub fn extended_erase(&mut self, pages: &[u16]) -> anyhow::Result<()> {
+    pub fn extended_erase(&mut self, pages: &[u16]) -> Result<()> {
         info! {"erasing {} pages with extended erase command", pages.len()}
         if pages.is_empty() {
             warn! {"no pages to erase, doing nothing"};
@@ -466,11 +836,11 @@ impl AN3155 {
         self.write_command(BootloaderCommand::ExtendedErase)?;
         self.write_with_checksum(&buf)?;
         self.serial.flush()?;
-        self.read_ack()
+        self.read_ack(BootloaderCommand::ExtendedErase)
     }
 
     /// Global erase with standard erase command
-    pub fn extended_global_erase(&mut self, bank: BankErase) -> anyhow::Result<()> {
+    pub fn extended_global_erase(&mut self, bank: BankErase) -> Result<()> {
         let buf = match bank {
             BankErase::Global => &[0xFF, 0xFF, 0x00][..],
             BankErase::Bank1 => &[0xFF, 0xFE, 0x01][..],
@@ -480,10 +850,15 @@ impl AN3155 {
         self.write_command(BootloaderCommand::ExtendedErase)?;
         self.write(buf)?;
         self.serial.flush()?;
-        self.read_ack()
+        self.read_ack(BootloaderCommand::ExtendedErase)
     }
 
-    pub fn write_memory(&mut self, address: u32, bytes: &[u8]) -> anyhow::Result<()> {
+    /// Write up to 256 bytes of memory starting at `address`
+    pub fn write_memory(&mut self, address: u32, bytes: &[u8]) -> Result<()> {
+        self.retry(|an3155| an3155.write_memory_once(address, bytes))
+    }
+
+    fn write_memory_once(&mut self, address: u32, bytes: &[u8]) -> Result<()> {
         info! {"writing {} bytes to memory starting at address: {:08X}", bytes.len(), address};
         let num_bytes = bytes.len();
         if num_bytes == 0 {
@@ -492,24 +867,29 @@ impl AN3155 {
         }
 
         if num_bytes > MAX_WRITE_BYTES_COUNT {
-            return Err(Error::WriteBytesCount(bytes.len()).into());
+            return Err(Error::WriteBytesCount(bytes.len()));
         }
         let address_as_bytes = address.to_be_bytes();
 
         self.write_command(BootloaderCommand::WriteMemory)?;
         self.write_with_checksum(&address_as_bytes[..])?;
         self.serial.flush()?;
-        self.read_ack()?;
+        self.read_ack(BootloaderCommand::WriteMemory)?;
 
         let n = (num_bytes - 1) as u8;
         let checksum = bytes.iter().fold(n, |acc, b| acc ^ b);
         self.write(&[n][..])?;
         self.write(bytes)?;
         self.write(&[checksum][..])?;
-        self.read_ack()
+        self.read_ack(BootloaderCommand::WriteMemory)
+    }
+
+    /// Read up to 256 bytes of memory starting at `address`
+    pub fn read_memory(&mut self, address: u32, bytes: &mut [u8]) -> Result<()> {
+        self.retry(|an3155| an3155.read_memory_once(address, bytes))
     }
 
-    pub fn read_memory(&mut self, address: u32, bytes: &mut [u8]) -> anyhow::Result<()> {
+    fn read_memory_once(&mut self, address: u32, bytes: &mut [u8]) -> Result<()> {
         info! {"reading {} bytes to memory starting at address: {:08X}", bytes.len(), address};
         if bytes.is_empty() {
             warn! {"no bytes to read, doing nothing"};
@@ -517,28 +897,273 @@ impl AN3155 {
         }
 
         if bytes.len() > MAX_READ_BYTES_COUNT {
-            return Err(Error::WriteBytesCount(bytes.len()).into());
+            return Err(Error::WriteBytesCount(bytes.len()));
         }
         let address_as_bytes = address.to_be_bytes();
 
         self.write_command(BootloaderCommand::ReadMemory)?;
         self.write_with_checksum(&address_as_bytes[..])?;
         self.serial.flush()?;
-        self.read_ack()?;
+        self.read_ack(BootloaderCommand::ReadMemory)?;
 
         let num_bytes = bytes.len();
         let n = (num_bytes - 1) as u8;
         let checksum = !n;
         self.write(&[n, checksum][..])?;
         self.serial.flush()?;
-        self.read_ack()?;
+        self.read_ack(BootloaderCommand::ReadMemory)?;
 
         self.read_exact(bytes)
     }
 
-    pub fn write_unprotect(&mut self) -> anyhow::Result<()> {
+    /// Jump to application code at `address`
+    ///
+    /// `address` is the start of the application's vector table.  The
+    /// bootloader ACKs the command and then the address before jumping, after
+    /// which it no longer answers on the serial link.
+    pub fn go(&mut self, address: u32) -> Result<()> {
+        info! {"jumping to application at address: {:08X}", address};
+        self.write_command(BootloaderCommand::Go)?;
+        self.write_with_checksum(&address.to_be_bytes()[..])?;
+        self.serial.flush()?;
+        self.read_ack(BootloaderCommand::Go)
+            .context("Bootloader rejected the Go address")
+    }
+
+    /// Compute the CRC of `size` bytes of memory on the chip
+    ///
+    /// Uses the default polynomial and initial value of the STM32 CRC unit,
+    /// so the result can be compared with [`crc32`].
+    pub fn get_checksum(&mut self, address: u32, size: u32) -> Result<u32> {
+        self.get_checksum_with_polynomial(address, size, CRC32_POLYNOMIAL, CRC32_INITIAL)
+    }
+
+    /// Compute the CRC of `size` bytes of memory on the chip with the given polynomial
+    pub fn get_checksum_with_polynomial(
+        &mut self,
+        address: u32,
+        size: u32,
+        polynomial: u32,
+        initial: u32,
+    ) -> Result<u32> {
+        self.retry(|an3155| an3155.get_checksum_once(address, size, polynomial, initial))
+    }
+
+    fn get_checksum_once(
+        &mut self,
+        address: u32,
+        size: u32,
+        polynomial: u32,
+        initial: u32,
+    ) -> Result<u32> {
+        info! {"computing checksum of {} bytes starting at address: {:08X}", size, address};
+        if size == 0 || !size.is_multiple_of(4) {
+            return Err(Error::ChecksumSize(size));
+        }
+
+        self.write_command(BootloaderCommand::GetChecksum)?;
+        debug! {"sending address"};
+        self.write_with_checksum(&address.to_be_bytes()[..])?;
+        self.serial.flush()?;
+        self.read_ack(BootloaderCommand::GetChecksum)?;
+
+        debug! {"sending size"};
+        self.write_with_checksum(&size.to_be_bytes()[..])?;
+        self.serial.flush()?;
+        self.read_ack(BootloaderCommand::GetChecksum)?;
+
+        debug! {"sending CRC polynomial"};
+        self.write_with_checksum(&polynomial.to_be_bytes()[..])?;
+        self.serial.flush()?;
+        self.read_ack(BootloaderCommand::GetChecksum)?;
+
+        debug! {"sending CRC initial value"};
+        self.write_with_checksum(&initial.to_be_bytes()[..])?;
+        self.serial.flush()?;
+        self.read_ack(BootloaderCommand::GetChecksum)?;
+
+        let mut buf = [0u8; 5];
+        self.read_exact(&mut buf)
+            .context("Failed to read checksum value")?;
+        if buf.iter().fold(0u8, |acc, b| acc ^ b) != 0 {
+            return Err(Error::ResponseChecksum);
+        }
+        Ok(u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]))
+    }
+
+    /// Send a 2-byte big endian length followed by the data and checksum
+    fn write_with_length(&mut self, bytes: &[u8]) -> Result<usize> {
+        let mut buf = Vec::with_capacity(bytes.len() + 2);
+        buf.extend_from_slice(&(bytes.len() as u16).to_be_bytes()[..]);
+        buf.extend_from_slice(bytes);
+        self.write_with_checksum(&buf)
+    }
+
+    /// Read a 2-byte big endian length followed by that many bytes
+    fn read_with_length(&mut self) -> Result<Vec<u8>> {
+        let mut len = [0u8; 2];
+        self.read_exact(&mut len)?;
+        let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Send a Special command
+    ///
+    /// The meaning of `opcode` and `data` depends on the product, see the
+    /// device's bootloader documentation.
+    pub fn special(&mut self, opcode: u16, data: &[u8]) -> Result<SpecialResponse> {
+        info! {"sending special command opcode 0x{:04X} with {} data bytes", opcode, data.len()};
+        if data.len() > MAX_SPECIAL_DATA_COUNT {
+            return Err(Error::SpecialDataCount {
+                len: data.len(),
+                max: MAX_SPECIAL_DATA_COUNT,
+            });
+        }
+
+        self.write_command(BootloaderCommand::Special)?;
+        debug! {"sending opcode"};
+        self.write_with_checksum(&opcode.to_be_bytes()[..])?;
+        self.serial.flush()?;
+        self.read_ack(BootloaderCommand::Special)?;
+
+        debug! {"sending data"};
+        self.write_with_length(data)?;
+        self.serial.flush()?;
+        self.read_ack(BootloaderCommand::Special)
+            .with_context(|| format! {"Special command opcode 0x{opcode:04X} failed"})?;
+
+        let data = self
+            .read_with_length()
+            .context("Failed to read special command data")?;
+        let status = self
+            .read_with_length()
+            .context("Failed to read special command status")?;
+        self.read_ack(BootloaderCommand::Special)?;
+        Ok(SpecialResponse { data, status })
+    }
+
+    /// Send an ExtendedSpecial command, returning the status bytes
+    ///
+    /// Up to 128 bytes of `data` and 1024 bytes of `file_data` can be sent.
+    pub fn extended_special(
+        &mut self,
+        opcode: u16,
+        data: &[u8],
+        file_data: &[u8],
+    ) -> Result<Vec<u8>> {
+        info! {"sending extended special command opcode 0x{:04X} with {} data bytes and {} file data bytes",
+        opcode, data.len(), file_data.len()};
+        if data.len() > MAX_SPECIAL_DATA_COUNT {
+            return Err(Error::SpecialDataCount {
+                len: data.len(),
+                max: MAX_SPECIAL_DATA_COUNT,
+            });
+        }
+        if file_data.len() > MAX_EXTENDED_SPECIAL_DATA_COUNT {
+            return Err(Error::SpecialDataCount {
+                len: file_data.len(),
+                max: MAX_EXTENDED_SPECIAL_DATA_COUNT,
+            });
+        }
+
+        self.write_command(BootloaderCommand::ExtendedSpecial)?;
+        debug! {"sending opcode"};
+        self.write_with_checksum(&opcode.to_be_bytes()[..])?;
+        self.serial.flush()?;
+        self.read_ack(BootloaderCommand::ExtendedSpecial)?;
+
+        debug! {"sending data"};
+        self.write_with_length(data)?;
+        self.serial.flush()?;
+        self.read_ack(BootloaderCommand::ExtendedSpecial)?;
+
+        debug! {"sending file data"};
+        self.write_with_length(file_data)?;
+        self.serial.flush()?;
+        self.read_ack(BootloaderCommand::ExtendedSpecial)
+            .with_context(|| format! {"Extended special command opcode 0x{opcode:04X} failed"})?;
+
+        let status = self
+            .read_with_length()
+            .context("Failed to read extended special command status")?;
+        self.read_ack(BootloaderCommand::ExtendedSpecial)?;
+        Ok(status)
+    }
+
+    /// Enable write protection for the given flash sectors
+    ///
+    /// Sectors are the write protection sectors of `layout`, which may group
+    /// several pages, and are validated before anything is sent.  The
+    /// bootloader resets the chip once protection is enabled; the session is
+    /// synced again before returning.
+    pub fn write_protect(&mut self, layout: &FlashLayout, sectors: &[u8]) -> Result<()> {
+        info! {"enabling FLASH memory write protection for {} sectors", sectors.len()};
+        if sectors.is_empty() {
+            warn! {"no sectors to protect, doing nothing"};
+            return Ok(());
+        }
+
+        if sectors.len() > MAX_WRITE_PROTECT_SECTOR_COUNT {
+            return Err(Error::WriteProtectSectorCount(sectors.len()));
+        }
+
+        let count = layout.wrp_sector_count();
+        if let Some(&sector) = sectors.iter().find(|&&s| s as usize >= count) {
+            return Err(Error::SectorOutOfRange {
+                sector: sector as usize,
+                count,
+            });
+        }
+
+        let n = (sectors.len() - 1) as u8;
+        let mut buf = Vec::with_capacity(sectors.len() + 1);
+        buf.push(n);
+        buf.extend_from_slice(sectors);
+
+        self.write_command(BootloaderCommand::WriteProtect)?;
+        debug! {"sending list of sectors to protect"};
+        self.write_with_checksum(&buf)?;
+        self.serial.flush()?;
+        self.read_ack(BootloaderCommand::WriteProtect)
+            .context("Failed to enable write protection")?;
+        self.resync_after_reset()
+    }
+
+    /// Disable write protection for all flash sectors
+    ///
+    /// The bootloader resets the chip once protection is removed; the
+    /// session is synced again before returning.
+    pub fn write_unprotect(&mut self) -> Result<()> {
         info! {"disabling FLASH memory write protection"};
         self.write_command(BootloaderCommand::WriteUnprotect)?;
-        self.read_ack()
+        self.read_ack(BootloaderCommand::WriteUnprotect)
+            .context("Failed to disable write protection")?;
+        self.resync_after_reset()
+    }
+
+    /// Enable readout protection
+    ///
+    /// The bootloader resets the chip once protection is enabled; the
+    /// session is synced again before returning.
+    pub fn readout_protect(&mut self) -> Result<()> {
+        info! {"enabling FLASH memory readout protection"};
+        self.write_command(BootloaderCommand::ReadoutProtect)?;
+        self.read_ack(BootloaderCommand::ReadoutProtect)
+            .context("Failed to enable readout protection")?;
+        self.resync_after_reset()
+    }
+
+    /// Disable readout protection
+    ///
+    /// **This mass erases the flash memory.**  The bootloader resets the chip
+    /// once protection is removed; the session is synced again before
+    /// returning.
+    pub fn readout_unprotect(&mut self) -> Result<()> {
+        info! {"disabling FLASH memory readout protection"};
+        self.write_command(BootloaderCommand::ReadoutUnprotect)?;
+        self.read_ack(BootloaderCommand::ReadoutUnprotect)
+            .context("Failed to disable readout protection")?;
+        self.resync_after_reset()
     }
 }
@@ -0,0 +1,415 @@
+//! Operations on whole images and memory regions, built from single commands
+
+use crate::{
+    crc32, error::Context, progress::Tracker, BankErase, BootloaderCommand, Device, EraseCommand,
+    Error, FlashLayout, Image, Phase, ProgressObserver, Result, Transport, AN3155,
+    MAX_ERASE_PAGE_COUNT, MAX_READ_BYTES_COUNT, MAX_WRITE_BYTES_COUNT,
+};
+use log::{debug, info, warn};
+use std::ops::Range;
+
+/// SRAM region of the Cortex-M memory map, where images for devices missing
+/// from the device table may place RAM segments
+const SRAM_REGION: Range<u32> = 0x2000_0000..0x4000_0000;
+
+/// Flash memory erased by [`AN3155::flash_image`] before writing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EraseStrategy {
+    /// Erase only the pages touched by the image
+    #[default]
+    Pages,
+    /// Erase all flash memory
+    Mass,
+    /// Don't erase, the pages must already be erased
+    Skip,
+}
+
+/// How [`AN3155::flash_image`] checks the written data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// Use the bootloader checksum if supported, otherwise read back
+    #[default]
+    Auto,
+    /// Compare the CRC computed by the bootloader
+    Crc,
+    /// Read back every written byte
+    ReadBack,
+    /// Don't verify
+    Skip,
+}
+
+/// Options for [`AN3155::flash_image`]
+///
+/// # Example
+/// ```
+/// # use stm32_an3155_rs::{EraseStrategy, FlashOptions, VerifyMode};
+/// let options = FlashOptions::default()
+///     .and_erase(EraseStrategy::Mass)
+///     .and_verify(VerifyMode::ReadBack)
+///     .and_go(true);
+/// assert!(options.layout.is_none());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FlashOptions {
+    pub erase: EraseStrategy,
+    pub verify: VerifyMode,
+    /// Flash layout used to check the image and find the pages to erase,
+    /// detected from the product ID when not given
+    pub layout: Option<FlashLayout>,
+    /// Start the firmware after flashing, from the image's
+    /// [vector table](Image::vector_table)
+    pub go: bool,
+}
+
+impl FlashOptions {
+    pub fn and_erase(mut self, erase: EraseStrategy) -> Self {
+        self.erase = erase;
+        self
+    }
+
+    pub fn and_verify(mut self, verify: VerifyMode) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    pub fn and_layout(mut self, layout: FlashLayout) -> Self {
+        self.layout = Some(layout);
+        self
+    }
+
+    pub fn and_go(mut self, go: bool) -> Self {
+        self.go = go;
+        self
+    }
+}
+
+/// Run of bytes in memory that differs from the expected contents
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Address of the first differing byte
+    pub address: u32,
+    /// Expected contents
+    pub expected: Vec<u8>,
+    /// Contents read from memory
+    pub actual: Vec<u8>,
+}
+
+/// Find every run of differing bytes between expected and actual memory contents
+fn compare(address: u32, expected: &[u8], actual: &[u8]) -> Vec<Mismatch> {
+    let mut mismatches: Vec<Mismatch> = Vec::new();
+    let mut previous = None;
+    for (offset, (&e, &a)) in expected.iter().zip(actual).enumerate() {
+        if e == a {
+            continue;
+        }
+        match mismatches.last_mut() {
+            Some(mismatch) if previous.map(|p| p + 1) == Some(offset) => {
+                mismatch.expected.push(e);
+                mismatch.actual.push(a);
+            }
+            _ => mismatches.push(Mismatch {
+                address: address + offset as u32,
+                expected: vec![e],
+                actual: vec![a],
+            }),
+        }
+        previous = Some(offset);
+    }
+    mismatches
+}
+
+/// Check that no segment of an image runs past the end of the address space
+fn check_address_space(image: &Image) -> Result<()> {
+    match image
+        .segments
+        .iter()
+        .find(|segment| segment.end() > 1 << 32)
+    {
+        Some(segment) => Err(Error::AddressOutOfRange {
+            address: segment.address,
+            len: segment.data.len(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Check that every segment of an image lies wholly in flash or in RAM
+fn check_segments(image: &Image, layout: &FlashLayout, ram: &Range<u32>) -> Result<()> {
+    for segment in &image.segments {
+        let len = segment.data.len();
+        if layout.contains(segment.address, len as u32) {
+            continue;
+        }
+        if segment.address < ram.start || segment.end() > ram.end as u64 {
+            let address = segment.address;
+            return Err(Error::AddressOutOfRange { address, len });
+        }
+        debug! {"segment at 0x{:08X} is in RAM, it is not erased", segment.address};
+    }
+    Ok(())
+}
+
+/// Every page touched by the flash segments of an image, in ascending order
+fn pages_to_erase(image: &Image, layout: &FlashLayout) -> Vec<u32> {
+    let mut pages: Vec<u32> = Vec::new();
+    for segment in &image.segments {
+        let len = segment.data.len() as u32;
+        if let Some(touched) = layout.sectors_in_range(segment.address, len) {
+            debug! {"segment at 0x{:08X} touches pages {:?}", segment.address, touched};
+            pages.extend(touched.map(|page| page as u32));
+        }
+    }
+    pages.sort_unstable();
+    pages.dedup();
+    pages
+}
+
+impl<T: Transport> AN3155<T> {
+    /// Erase, write and verify an image, then optionally start it
+    ///
+    /// Segments may be in flash or in the RAM available to the host; RAM
+    /// segments are written without erasing.  Every segment is checked
+    /// against the address space, and against flash and RAM if the flash
+    /// layout is known, before anything is erased.
+    ///
+    /// # Example
+    /// ```
+    /// # use stm32_an3155_rs::{sim::Simulator, Builder, FlashOptions, Image};
+    /// let sim = Simulator::new().and_flash_contents(0x0800_0000, &[0u8; 4]);
+    /// let mut an3155 = Builder::with_transport(sim).initialize()?;
+    ///
+    /// let image = Image::from_binary(0x0800_0000, vec![1, 2, 3, 4]);
+    /// an3155.flash_image(&image, &FlashOptions::default().and_go(true), &mut ())?;
+    /// assert_eq!([1, 2, 3, 4], an3155.transport().flash()[..4]);
+    /// assert_eq!(Some(0x0800_0000), an3155.transport().application_address());
+    /// # Ok::<(), stm32_an3155_rs::Error>(())
+    /// ```
+    pub fn flash_image(
+        &mut self,
+        image: &Image,
+        options: &FlashOptions,
+        progress: &mut dyn ProgressObserver,
+    ) -> Result<()> {
+        info! {"flashing {} bytes in {} segments", image.size(), image.segments.len()};
+        check_address_space(image)?;
+        let device = self.get_device()?;
+        let layout = options
+            .layout
+            .clone()
+            .or_else(|| device.map(|device| device.flash.clone()));
+        let ram = device.map_or(SRAM_REGION, |device| device.ram.clone());
+        if let Some(layout) = &layout {
+            check_segments(image, layout, &ram)?;
+        }
+
+        match options.erase {
+            EraseStrategy::Pages => {
+                let layout = match layout {
+                    Some(layout) => layout,
+                    None => self.get_flash_layout()?,
+                };
+                let pages = pages_to_erase(image, &layout);
+                self.erase_pages(&pages, progress)
+                    .context("Failed to erase flash")?;
+            }
+            EraseStrategy::Mass => self.mass_erase().context("Failed to erase flash")?,
+            EraseStrategy::Skip => {}
+        }
+
+        self.write_image(image, progress)?;
+
+        let verify = match options.verify {
+            VerifyMode::Auto => match self.supports(BootloaderCommand::GetChecksum)? {
+                true => VerifyMode::Crc,
+                false => VerifyMode::ReadBack,
+            },
+            mode => mode,
+        };
+        debug! {"verification mode: {verify:?}"};
+        match verify {
+            VerifyMode::ReadBack => {
+                let mismatches = self.verify_image(image, progress)?;
+                if let Some(mismatch) = mismatches.first() {
+                    let address = mismatch.address;
+                    return Err(Error::VerifyMismatch { address }).with_context(|| {
+                        let (expected, actual) = (mismatch.expected[0], mismatch.actual[0]);
+                        format! {"Expected 0x{expected:02X}, read 0x{actual:02X}"}
+                    });
+                }
+            }
+            VerifyMode::Crc => self.verify_image_checksum(image, progress)?,
+            _ => {}
+        }
+
+        if options.go {
+            match image.vector_table() {
+                Some(address) => self.go(address)?,
+                None => warn! {"image is empty, not starting firmware"},
+            }
+        }
+        Ok(())
+    }
+
+    /// Erase all flash memory with whichever erase command the bootloader supports
+    pub fn mass_erase(&mut self) -> Result<()> {
+        match self.get_erase_command()? {
+            EraseCommand::Erase => self.standard_global_erase(),
+            EraseCommand::ExtendedErase => self.extended_global_erase(BankErase::Global),
+        }
+    }
+
+    /// Flash layout of the connected device, looked up from its product ID
+    pub fn get_flash_layout(&mut self) -> Result<FlashLayout> {
+        let pid = self.get_id()?;
+        let device = Device::from_pid(pid).ok_or(Error::UnknownDevice(pid))?;
+        debug! {"using flash layout for {}", device.name};
+        Ok(device.flash.clone())
+    }
+
+    /// Erase flash pages with whichever erase command the bootloader supports
+    ///
+    /// Progress is reported in pages.
+    pub fn erase_pages(
+        &mut self,
+        pages: &[u32],
+        progress: &mut dyn ProgressObserver,
+    ) -> Result<()> {
+        let command = self.get_erase_command()?;
+        let max = match command {
+            EraseCommand::Erase => u8::MAX.into(),
+            EraseCommand::ExtendedErase => u16::MAX.into(),
+        };
+        if let Some(&page) = pages.iter().find(|&&page| page > max) {
+            return Err(Error::PageOutOfRange { page, max });
+        }
+        debug! {"erasing pages {pages:?} with {command:?} command"};
+
+        let mut tracker = Tracker::start(progress, Phase::Erase, pages.len() as u64);
+        for chunk in pages.chunks(MAX_ERASE_PAGE_COUNT) {
+            match command {
+                EraseCommand::Erase => {
+                    let chunk: Vec<u8> = chunk.iter().map(|&page| page as u8).collect();
+                    self.standard_erase(&chunk)?;
+                }
+                EraseCommand::ExtendedErase => {
+                    let chunk: Vec<u16> = chunk.iter().map(|&page| page as u16).collect();
+                    self.extended_erase(&chunk)?;
+                }
+            }
+            tracker.advance(chunk.len() as u64);
+        }
+        Ok(())
+    }
+
+    /// Write every segment of an image to memory
+    ///
+    /// Flash must already be erased.
+    pub fn write_image(
+        &mut self,
+        image: &Image,
+        progress: &mut dyn ProgressObserver,
+    ) -> Result<()> {
+        check_address_space(image)?;
+        let mut tracker = Tracker::start(progress, Phase::Write, image.size() as u64);
+        for segment in &image.segments {
+            for (index, chunk) in segment.data.chunks(MAX_WRITE_BYTES_COUNT).enumerate() {
+                let address = segment.address + (index * MAX_WRITE_BYTES_COUNT) as u32;
+                self.write_memory(address, chunk)?;
+                tracker.advance(chunk.len() as u64);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read back every segment of an image and compare it with memory
+    ///
+    /// Returns each run of bytes that differs, so an empty list means memory
+    /// matches the image.
+    pub fn verify_image(
+        &mut self,
+        image: &Image,
+        progress: &mut dyn ProgressObserver,
+    ) -> Result<Vec<Mismatch>> {
+        check_address_space(image)?;
+        let mut tracker = Tracker::start(progress, Phase::Verify, image.size() as u64);
+        let mut mismatches = Vec::new();
+        for segment in &image.segments {
+            let mut actual = vec![0u8; segment.data.len()];
+            for (index, chunk) in actual.chunks_mut(MAX_READ_BYTES_COUNT).enumerate() {
+                let address = segment.address + (index * MAX_READ_BYTES_COUNT) as u32;
+                self.read_memory(address, chunk)?;
+                tracker.advance(chunk.len() as u64);
+            }
+            mismatches.extend(compare(segment.address, &segment.data, &actual));
+        }
+        Ok(mismatches)
+    }
+
+    /// Compare the CRC computed by the bootloader with the CRC of each segment
+    ///
+    /// The bootloader only checksums whole words, so bytes of a segment
+    /// outside its word-aligned interior are read back and compared instead.
+    pub fn verify_image_checksum(
+        &mut self,
+        image: &Image,
+        progress: &mut dyn ProgressObserver,
+    ) -> Result<()> {
+        check_address_space(image)?;
+        let mut tracker = Tracker::start(progress, Phase::Verify, image.size() as u64);
+        for segment in &image.segments {
+            let len = segment.data.len();
+            let head = ((segment.address.wrapping_neg() & 3) as usize).min(len);
+            let tail = head + (len - head) / 4 * 4;
+            let start = segment.address + head as u32;
+
+            if tail > head {
+                let expected = crc32(&segment.data[head..tail]);
+                let actual = self.get_checksum(start, (tail - head) as u32)?;
+                debug! {"expected CRC: 0x{expected:08X}, device CRC: 0x{actual:08X}"};
+                if expected != actual {
+                    return Err(Error::VerifyMismatch { address: start }).with_context(|| {
+                        format! {"Device CRC 0x{actual:08X} does not match expected 0x{expected:08X}"}
+                    });
+                }
+            }
+            for range in [0..head, tail..len] {
+                if range.is_empty() {
+                    continue;
+                }
+                let address = segment.address + range.start as u32;
+                let expected = &segment.data[range];
+                let mut actual = vec![0u8; expected.len()];
+                self.read_memory(address, &mut actual)?;
+                if let Some(mismatch) = compare(address, expected, &actual).first() {
+                    let address = mismatch.address;
+                    return Err(Error::VerifyMismatch { address }).with_context(|| {
+                        let (expected, actual) = (mismatch.expected[0], mismatch.actual[0]);
+                        format! {"Expected 0x{expected:02X}, read 0x{actual:02X}"}
+                    });
+                }
+            }
+            tracker.advance(len as u64);
+        }
+        Ok(())
+    }
+
+    /// Read any amount of memory starting at `address`
+    pub fn read_region(
+        &mut self,
+        address: u32,
+        bytes: &mut [u8],
+        progress: &mut dyn ProgressObserver,
+    ) -> Result<()> {
+        if address as u64 + bytes.len() as u64 > 1 << 32 {
+            let len = bytes.len();
+            return Err(Error::AddressOutOfRange { address, len });
+        }
+        let mut tracker = Tracker::start(progress, Phase::Read, bytes.len() as u64);
+        for (index, chunk) in bytes.chunks_mut(MAX_READ_BYTES_COUNT).enumerate() {
+            let address = address + (index * MAX_READ_BYTES_COUNT) as u32;
+            self.read_memory(address, chunk)?;
+            tracker.advance(chunk.len() as u64);
+        }
+        Ok(())
+    }
+}
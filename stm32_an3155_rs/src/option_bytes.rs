@@ -0,0 +1,565 @@
+use crate::{error::Context, Device, Error, Result, Transport, AN3155};
+use log::{debug, info, warn};
+
+/// Readout protection level that cannot be undone
+const RDP_LEVEL_2: u32 = 0xCC;
+
+/// Bit field of an option byte value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OptionField {
+    /// Name used in the reference manual
+    pub name: &'static str,
+    /// Index of the value holding the field
+    pub value: usize,
+    /// Position of the lowest bit in the value
+    pub shift: u32,
+    /// Width in bits
+    pub bits: u32,
+    pub description: &'static str,
+}
+
+impl OptionField {
+    /// Largest value the field can hold
+    pub fn max(&self) -> u32 {
+        u32::MAX >> (32 - self.bits)
+    }
+}
+
+const fn field(
+    name: &'static str,
+    value: usize,
+    shift: u32,
+    bits: u32,
+    description: &'static str,
+) -> OptionField {
+    OptionField {
+        name,
+        value,
+        shift,
+        bits,
+        description,
+    }
+}
+
+/// How a family stores its option bytes in memory
+///
+/// Option bytes are a list of little-endian values of `width` bytes.  On
+/// most families each value is followed by its bitwise complement, which the
+/// flash controller checks when loading them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OptionBytesLayout {
+    /// Families using the layout
+    pub family: &'static str,
+    /// Bytes per value
+    pub width: usize,
+    /// Whether each value is followed by its complement
+    pub complement: bool,
+    /// Number of values
+    pub count: usize,
+    pub fields: &'static [OptionField],
+}
+
+impl OptionBytesLayout {
+    /// Bytes taken up in memory, including complements
+    pub fn size(&self) -> usize {
+        let stride = match self.complement {
+            true => 2 * self.width,
+            false => self.width,
+        };
+        self.count * stride
+    }
+
+    /// Look up a field by name, ignoring case
+    pub fn field(&self, name: &str) -> Option<&'static OptionField> {
+        self.fields
+            .iter()
+            .find(|f| f.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// STM32F0
+pub(crate) const F0_OPTION_LAYOUT: OptionBytesLayout = OptionBytesLayout {
+    family: "STM32F0",
+    width: 1,
+    complement: true,
+    count: 8,
+    fields: &[
+        field("RDP", 0, 0, 8, "Readout protection, 0xAA disables it"),
+        field("WDG_SW", 1, 0, 1, "Watchdog started by software"),
+        field("nRST_STOP", 1, 1, 1, "No reset when entering Stop mode"),
+        field("nRST_STDBY", 1, 2, 1, "No reset when entering Standby mode"),
+        field("nBOOT0", 1, 3, 1, "Inverted BOOT0 when not from the pin"),
+        field(
+            "nBOOT1",
+            1,
+            4,
+            1,
+            "Boot system memory (1) or SRAM (0) with BOOT0",
+        ),
+        field("VDDA_MONITOR", 1, 5, 1, "VDDA supervisor enabled"),
+        field("RAM_PARITY_CHECK", 1, 6, 1, "SRAM parity check disabled"),
+        field("BOOT_SEL", 1, 7, 1, "BOOT0 from the pin (1) or nBOOT0 (0)"),
+        field("DATA0", 2, 0, 8, "User data byte 0"),
+        field("DATA1", 3, 0, 8, "User data byte 1"),
+        field("WRP0", 4, 0, 8, "Write protection, bit clear protects"),
+        field("WRP1", 5, 0, 8, "Write protection, bit clear protects"),
+        field("WRP2", 6, 0, 8, "Write protection, bit clear protects"),
+        field("WRP3", 7, 0, 8, "Write protection, bit clear protects"),
+    ],
+};
+
+/// STM32F1
+pub(crate) const F1_OPTION_LAYOUT: OptionBytesLayout = OptionBytesLayout {
+    family: "STM32F1",
+    width: 1,
+    complement: true,
+    count: 8,
+    fields: &[
+        field("RDP", 0, 0, 8, "Readout protection, 0xA5 disables it"),
+        field("WDG_SW", 1, 0, 1, "Watchdog started by software"),
+        field("nRST_STOP", 1, 1, 1, "No reset when entering Stop mode"),
+        field("nRST_STDBY", 1, 2, 1, "No reset when entering Standby mode"),
+        field("DATA0", 2, 0, 8, "User data byte 0"),
+        field("DATA1", 3, 0, 8, "User data byte 1"),
+        field("WRP0", 4, 0, 8, "Write protection, bit clear protects"),
+        field("WRP1", 5, 0, 8, "Write protection, bit clear protects"),
+        field("WRP2", 6, 0, 8, "Write protection, bit clear protects"),
+        field("WRP3", 7, 0, 8, "Write protection, bit clear protects"),
+    ],
+};
+
+/// STM32F3
+pub(crate) const F3_OPTION_LAYOUT: OptionBytesLayout = OptionBytesLayout {
+    family: "STM32F3",
+    width: 1,
+    complement: true,
+    count: 8,
+    fields: &[
+        field("RDP", 0, 0, 8, "Readout protection, 0xAA disables it"),
+        field("WDG_SW", 1, 0, 1, "Watchdog started by software"),
+        field("nRST_STOP", 1, 1, 1, "No reset when entering Stop mode"),
+        field("nRST_STDBY", 1, 2, 1, "No reset when entering Standby mode"),
+        field(
+            "nBOOT1",
+            1,
+            4,
+            1,
+            "Boot system memory (1) or SRAM (0) with BOOT0",
+        ),
+        field("VDDA_MONITOR", 1, 5, 1, "VDDA supervisor enabled"),
+        field("SRAM_PE", 1, 6, 1, "SRAM parity check disabled"),
+        field("DATA0", 2, 0, 8, "User data byte 0"),
+        field("DATA1", 3, 0, 8, "User data byte 1"),
+        field("WRP0", 4, 0, 8, "Write protection, bit clear protects"),
+        field("WRP1", 5, 0, 8, "Write protection, bit clear protects"),
+        field("WRP2", 6, 0, 8, "Write protection, bit clear protects"),
+        field("WRP3", 7, 0, 8, "Write protection, bit clear protects"),
+    ],
+};
+
+/// STM32F4, where the option bytes have no complement
+pub(crate) const F4_OPTION_LAYOUT: OptionBytesLayout = OptionBytesLayout {
+    family: "STM32F4",
+    width: 1,
+    complement: false,
+    count: 16,
+    fields: &[
+        field("BOR_LEV", 0, 2, 2, "Brownout reset level, 3 is off"),
+        field("WDG_SW", 0, 5, 1, "Watchdog started by software"),
+        field("nRST_STOP", 0, 6, 1, "No reset when entering Stop mode"),
+        field("nRST_STDBY", 0, 7, 1, "No reset when entering Standby mode"),
+        field("RDP", 1, 0, 8, "Readout protection, 0xAA disables it"),
+        field(
+            "nWRP_LOW",
+            8,
+            0,
+            8,
+            "Write protection of sectors 0-7, clear protects",
+        ),
+        field(
+            "nWRP_HIGH",
+            9,
+            0,
+            4,
+            "Write protection of sectors 8-11, clear protects",
+        ),
+    ],
+};
+
+/// STM32G0 FLASH_OPTR
+pub(crate) const G0_OPTION_LAYOUT: OptionBytesLayout = OptionBytesLayout {
+    family: "STM32G0",
+    width: 4,
+    complement: true,
+    count: 1,
+    fields: &[
+        field("RDP", 0, 0, 8, "Readout protection, 0xAA disables it"),
+        field("BOR_EN", 0, 8, 1, "Brownout reset enabled"),
+        field("BORF_LEV", 0, 9, 2, "Brownout reset falling threshold"),
+        field("BORR_LEV", 0, 11, 2, "Brownout reset rising threshold"),
+        field("nRST_STOP", 0, 13, 1, "No reset when entering Stop mode"),
+        field(
+            "nRST_STDBY",
+            0,
+            14,
+            1,
+            "No reset when entering Standby mode",
+        ),
+        field(
+            "nRST_SHDW",
+            0,
+            15,
+            1,
+            "No reset when entering Shutdown mode",
+        ),
+        field("IWDG_SW", 0, 16, 1, "Watchdog started by software"),
+        field("IWDG_STOP", 0, 17, 1, "Watchdog runs in Stop mode"),
+        field("IWDG_STDBY", 0, 18, 1, "Watchdog runs in Standby mode"),
+        field("WWDG_SW", 0, 19, 1, "Window watchdog started by software"),
+        field("RAM_PARITY_CHECK", 0, 22, 1, "SRAM parity check disabled"),
+        field(
+            "nBOOT_SEL",
+            0,
+            24,
+            1,
+            "BOOT0 from nBOOT0 (1) or the pin (0)",
+        ),
+        field(
+            "nBOOT1",
+            0,
+            25,
+            1,
+            "Boot system memory (1) or SRAM (0) with BOOT0",
+        ),
+        field("nBOOT0", 0, 26, 1, "Inverted BOOT0 when not from the pin"),
+        field("NRST_MODE", 0, 27, 2, "NRST pin mode"),
+        field("IRHEN", 0, 29, 1, "Internal reset holder enabled"),
+    ],
+};
+
+/// STM32G4 FLASH_OPTR
+pub(crate) const G4_OPTION_LAYOUT: OptionBytesLayout = OptionBytesLayout {
+    family: "STM32G4",
+    width: 4,
+    complement: true,
+    count: 1,
+    fields: &[
+        field("RDP", 0, 0, 8, "Readout protection, 0xAA disables it"),
+        field("BOR_LEV", 0, 8, 3, "Brownout reset level, 0 is the lowest"),
+        field("nRST_STOP", 0, 12, 1, "No reset when entering Stop mode"),
+        field(
+            "nRST_STDBY",
+            0,
+            13,
+            1,
+            "No reset when entering Standby mode",
+        ),
+        field(
+            "nRST_SHDW",
+            0,
+            14,
+            1,
+            "No reset when entering Shutdown mode",
+        ),
+        field("IWDG_SW", 0, 16, 1, "Watchdog started by software"),
+        field("IWDG_STOP", 0, 17, 1, "Watchdog runs in Stop mode"),
+        field("IWDG_STDBY", 0, 18, 1, "Watchdog runs in Standby mode"),
+        field("WWDG_SW", 0, 19, 1, "Window watchdog started by software"),
+        field("BFB2", 0, 20, 1, "Boot from bank 2"),
+        field(
+            "nBOOT1",
+            0,
+            23,
+            1,
+            "Boot system memory (1) or SRAM (0) with BOOT0",
+        ),
+        field("SRAM_PE", 0, 24, 1, "SRAM parity check disabled"),
+        field("CCMSRAM_RST", 0, 25, 1, "CCM SRAM kept on system reset"),
+        field("nSWBOOT0", 0, 26, 1, "BOOT0 from the pin (1) or nBOOT0 (0)"),
+        field("nBOOT0", 0, 27, 1, "Inverted BOOT0 when not from the pin"),
+        field("NRST_MODE", 0, 28, 2, "NRST pin mode"),
+        field("IRHEN", 0, 30, 1, "Internal reset holder enabled"),
+    ],
+};
+
+/// STM32L4 FLASH_OPTR
+pub(crate) const L4_OPTION_LAYOUT: OptionBytesLayout = OptionBytesLayout {
+    family: "STM32L4",
+    width: 4,
+    complement: true,
+    count: 1,
+    fields: &[
+        field("RDP", 0, 0, 8, "Readout protection, 0xAA disables it"),
+        field("BOR_LEV", 0, 8, 3, "Brownout reset level, 0 is the lowest"),
+        field("nRST_STOP", 0, 12, 1, "No reset when entering Stop mode"),
+        field(
+            "nRST_STDBY",
+            0,
+            13,
+            1,
+            "No reset when entering Standby mode",
+        ),
+        field(
+            "nRST_SHDW",
+            0,
+            14,
+            1,
+            "No reset when entering Shutdown mode",
+        ),
+        field("IWDG_SW", 0, 16, 1, "Watchdog started by software"),
+        field("IWDG_STOP", 0, 17, 1, "Watchdog runs in Stop mode"),
+        field("IWDG_STDBY", 0, 18, 1, "Watchdog runs in Standby mode"),
+        field("WWDG_SW", 0, 19, 1, "Window watchdog started by software"),
+        field("BFB2", 0, 20, 1, "Boot from bank 2"),
+        field("DUALBANK", 0, 21, 1, "Dual bank mode"),
+        field(
+            "nBOOT1",
+            0,
+            23,
+            1,
+            "Boot system memory (1) or SRAM (0) with BOOT0",
+        ),
+        field("SRAM2_PE", 0, 24, 1, "SRAM2 parity check disabled"),
+        field("SRAM2_RST", 0, 25, 1, "SRAM2 kept on system reset"),
+        field("nSWBOOT0", 0, 26, 1, "BOOT0 from the pin (1) or nBOOT0 (0)"),
+        field("nBOOT0", 0, 27, 1, "Inverted BOOT0 when not from the pin"),
+    ],
+};
+
+/// STM32L0, 16-bit values each followed by its complement
+pub(crate) const L0_OPTION_LAYOUT: OptionBytesLayout = OptionBytesLayout {
+    family: "STM32L0",
+    width: 2,
+    complement: true,
+    count: 2,
+    fields: &[
+        field("RDP", 0, 0, 8, "Readout protection, 0xAA disables it"),
+        field(
+            "WPRMOD",
+            0,
+            8,
+            1,
+            "Protection bits select PCROP (1) or WRP (0)",
+        ),
+        field(
+            "BOR_LEV",
+            1,
+            0,
+            4,
+            "Brownout reset level, 0 and 8 to 0xF are off",
+        ),
+        field("WDG_SW", 1, 4, 1, "Watchdog started by software"),
+        field("nRST_STOP", 1, 5, 1, "No reset when entering Stop mode"),
+        field("nRST_STDBY", 1, 6, 1, "No reset when entering Standby mode"),
+        field("BFB2", 1, 7, 1, "Boot from bank 2"),
+        field(
+            "nBOOT1",
+            1,
+            15,
+            1,
+            "Boot system memory (1) or SRAM (0) with BOOT0",
+        ),
+    ],
+};
+
+/// STM32L1, laid out like STM32L0
+pub(crate) const L1_OPTION_LAYOUT: OptionBytesLayout = OptionBytesLayout {
+    family: "STM32L1",
+    width: 2,
+    complement: true,
+    count: 2,
+    fields: &[
+        field("RDP", 0, 0, 8, "Readout protection, 0xAA disables it"),
+        field(
+            "SPRMOD",
+            0,
+            8,
+            1,
+            "Protection bits select PCROP (1) or WRP (0)",
+        ),
+        field(
+            "BOR_LEV",
+            1,
+            0,
+            4,
+            "Brownout reset level, 0 and 8 to 0xF are off",
+        ),
+        field("IWDG_SW", 1, 4, 1, "Watchdog started by software"),
+        field("nRST_STOP", 1, 5, 1, "No reset when entering Stop mode"),
+        field("nRST_STDBY", 1, 6, 1, "No reset when entering Standby mode"),
+        field("BFB2", 1, 7, 1, "Boot from bank 2"),
+    ],
+};
+
+/// Option bytes of a device, decoded into values
+///
+/// # Example
+/// ```
+/// # use stm32_an3155_rs::{sim::Simulator, Builder};
+/// let mut an3155 = Builder::with_transport(Simulator::new()).initialize()?;
+///
+/// let mut option_bytes = an3155.read_option_bytes()?;
+/// option_bytes.set("WDG_SW", 0)?;
+/// an3155.write_option_bytes(&option_bytes)?;
+///
+/// let option_bytes = an3155.read_option_bytes()?;
+/// assert_eq!(Some(0), option_bytes.get("WDG_SW"));
+/// assert!(option_bytes.complement_errors().is_empty());
+/// # Ok::<(), stm32_an3155_rs::Error>(())
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OptionBytes {
+    pub layout: &'static OptionBytesLayout,
+    /// Address of the first option byte
+    pub address: u32,
+    /// Values as stored, without complements
+    pub values: Vec<u32>,
+    /// Complements as stored, if the layout has them, kept up to date by
+    /// [`set`](Self::set)
+    pub complements: Vec<u32>,
+}
+
+impl OptionBytes {
+    /// Decode option bytes read from memory
+    pub fn decode(layout: &'static OptionBytesLayout, address: u32, bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != layout.size() {
+            return Err(Error::ResponseLength {
+                len: bytes.len(),
+                expected: layout.size(),
+            });
+        }
+        let words: Vec<u32> = bytes
+            .chunks(layout.width)
+            .map(|chunk| {
+                let mut word = [0u8; 4];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u32::from_le_bytes(word)
+            })
+            .collect();
+        let (values, complements) = match layout.complement {
+            true => (
+                words.iter().step_by(2).copied().collect(),
+                words.iter().skip(1).step_by(2).copied().collect(),
+            ),
+            false => (words, Vec::new()),
+        };
+        Ok(Self {
+            layout,
+            address,
+            values,
+            complements,
+        })
+    }
+
+    /// Encode the values for writing, with freshly computed complements
+    pub fn encode(&self) -> Vec<u8> {
+        let width = self.layout.width;
+        let mask = self.value_mask();
+        let mut bytes = Vec::with_capacity(self.layout.size());
+        for &value in &self.values {
+            bytes.extend_from_slice(&value.to_le_bytes()[..width]);
+            if self.layout.complement {
+                bytes.extend_from_slice(&(!value & mask).to_le_bytes()[..width]);
+            }
+        }
+        bytes
+    }
+
+    /// Addresses of values that do not match their stored complement
+    ///
+    /// The flash controller falls back to default option values when it
+    /// finds such a mismatch.
+    pub fn complement_errors(&self) -> Vec<u32> {
+        let mask = self.value_mask();
+        let stride = 2 * self.layout.width as u32;
+        self.values
+            .iter()
+            .zip(&self.complements)
+            .enumerate()
+            .filter(|(_, (&value, &complement))| value ^ complement != mask)
+            .map(|(index, _)| self.address + index as u32 * stride)
+            .collect()
+    }
+
+    /// Value of a field, by name
+    pub fn get(&self, name: &str) -> Option<u32> {
+        let field = self.layout.field(name)?;
+        Some((self.values[field.value] >> field.shift) & field.max())
+    }
+
+    /// Change a field, by name
+    ///
+    /// Setting RDP to 0xCC is refused, as level 2 readout protection
+    /// permanently disables the bootloader.
+    pub fn set(&mut self, name: &str, value: u32) -> Result<()> {
+        let field = self
+            .layout
+            .field(name)
+            .ok_or_else(|| Error::UnknownOptionField(name.to_owned()))?;
+        if value > field.max() {
+            return Err(Error::OptionFieldValue {
+                name: field.name,
+                value,
+                max: field.max(),
+            });
+        }
+        if field.name == "RDP" && value == RDP_LEVEL_2 {
+            return Err(Error::PermanentOptionValue {
+                name: field.name,
+                value,
+            });
+        }
+        let mask = field.max() << field.shift;
+        let stored = &mut self.values[field.value];
+        *stored = (*stored & !mask) | (value << field.shift);
+        let complement = !*stored & self.value_mask();
+        if let Some(stored) = self.complements.get_mut(field.value) {
+            *stored = complement;
+        }
+        debug! {"option field {} set to 0x{value:X}", field.name};
+        Ok(())
+    }
+
+    /// Every field with its value
+    pub fn fields(&self) -> impl Iterator<Item = (&'static OptionField, u32)> + '_ {
+        self.layout.fields.iter().map(|field| {
+            let value = (self.values[field.value] >> field.shift) & field.max();
+            (field, value)
+        })
+    }
+
+    fn value_mask(&self) -> u32 {
+        u32::MAX >> (32 - 8 * self.layout.width as u32)
+    }
+}
+
+impl<T: Transport> AN3155<T> {
+    /// Read and decode the option bytes, using the layout for the product ID
+    pub fn read_option_bytes(&mut self) -> Result<OptionBytes> {
+        let pid = self.get_id()?;
+        let device = Device::from_pid(pid).ok_or(Error::UnknownDevice(pid))?;
+        let layout = device.option_layout.ok_or(Error::UnknownOptionBytes(pid))?;
+        info! {"reading {} option bytes at 0x{:08X}", layout.family, device.option_bytes};
+
+        let mut bytes = vec![0u8; layout.size()];
+        self.read_region(device.option_bytes, &mut bytes, &mut ())
+            .context("Failed to read option bytes")?;
+        let option_bytes = OptionBytes::decode(layout, device.option_bytes, &bytes)?;
+        for address in option_bytes.complement_errors() {
+            warn! {"option byte at 0x{address:08X} does not match its complement"};
+        }
+        Ok(option_bytes)
+    }
+
+    /// Write option bytes with their complements
+    ///
+    /// The bootloader resets the chip to load the new option bytes; the
+    /// session is synced again before returning.
+    pub fn write_option_bytes(&mut self, option_bytes: &OptionBytes) -> Result<()> {
+        let bytes = option_bytes.encode();
+        info! {"writing {} option bytes at 0x{:08X}", bytes.len(), option_bytes.address};
+        self.write_memory(option_bytes.address, &bytes)
+            .context("Failed to write option bytes")?;
+        self.resync_after_reset()
+    }
+}
@@ -0,0 +1,117 @@
+use std::time::{Duration, Instant};
+
+/// Stage of a long running operation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Erase,
+    Write,
+    Verify,
+    Read,
+}
+
+/// Progress of a long running operation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Progress {
+    pub phase: Phase,
+    /// Bytes done so far, or pages when erasing
+    pub done: u64,
+    /// Bytes in the whole phase, or pages when erasing
+    pub total: u64,
+    /// Time since the phase started
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Bytes, or pages, per second since the phase started
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.done as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// Estimated time until the phase is done, at the current throughput
+    pub fn eta(&self) -> Option<Duration> {
+        let throughput = self.throughput();
+        (throughput > 0.0)
+            .then(|| Duration::from_secs_f64((self.total - self.done) as f64 / throughput))
+    }
+
+    /// Fraction of the phase that is done, from 0 to 1
+    pub fn fraction(&self) -> f64 {
+        match self.total {
+            0 => 1.0,
+            total => self.done as f64 / total as f64,
+        }
+    }
+}
+
+/// Receives progress updates from long running operations
+///
+/// Every phase is reported once before any work is done and then after
+/// every chunk, ending with `done == total`.  Closures taking a
+/// [`Progress`] can be used as observers, and `()` ignores all updates.
+///
+/// # Example
+/// ```
+/// # use stm32_an3155_rs::{sim::Simulator, Builder, Progress};
+/// let mut an3155 = Builder::with_transport(Simulator::new()).initialize()?;
+///
+/// let mut updates = Vec::new();
+/// let mut buf = [0u8; 600];
+/// an3155.read_region(0x0800_0000, &mut buf, &mut |p: &Progress| updates.push(p.done))?;
+/// assert_eq!(vec![0, 256, 512, 600], updates);
+/// # Ok::<(), stm32_an3155_rs::Error>(())
+/// ```
+pub trait ProgressObserver {
+    fn on_progress(&mut self, progress: &Progress);
+}
+
+impl ProgressObserver for () {
+    fn on_progress(&mut self, _progress: &Progress) {}
+}
+
+impl<F: FnMut(&Progress)> ProgressObserver for F {
+    fn on_progress(&mut self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// Reports the progress of one phase to an observer
+pub(crate) struct Tracker<'a> {
+    observer: &'a mut dyn ProgressObserver,
+    phase: Phase,
+    done: u64,
+    total: u64,
+    start: Instant,
+}
+
+impl<'a> Tracker<'a> {
+    /// Start a phase and report that nothing is done yet
+    pub(crate) fn start(observer: &'a mut dyn ProgressObserver, phase: Phase, total: u64) -> Self {
+        let mut tracker = Self {
+            observer,
+            phase,
+            done: 0,
+            total,
+            start: Instant::now(),
+        };
+        tracker.report();
+        tracker
+    }
+
+    /// Record `count` more bytes, or pages, as done
+    pub(crate) fn advance(&mut self, count: u64) {
+        self.done = (self.done + count).min(self.total);
+        self.report();
+    }
+
+    fn report(&mut self) {
+        self.observer.on_progress(&Progress {
+            phase: self.phase,
+            done: self.done,
+            total: self.total,
+            elapsed: self.start.elapsed(),
+        });
+    }
+}
@@ -0,0 +1,95 @@
+use crate::Error;
+use std::{io::ErrorKind as IoErrorKind, time::Duration};
+
+/// Default delay before the first retry
+pub const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// How failed commands are retried
+///
+/// Only commands that can safely be sent again are retried: reading and
+/// writing memory, checksums and the Get, GetVersion and GetId queries.
+/// Between attempts the session waits for the backoff delay, which is
+/// multiplied by `backoff_factor` after every retry, and gets back in step
+/// with the bootloader.
+///
+/// The default policy makes a single attempt.
+///
+/// # Example
+/// ```
+/// # use std::time::Duration;
+/// # use stm32_an3155_rs::RetryPolicy;
+/// let policy = RetryPolicy::new(3).and_backoff(Duration::from_millis(50), 2);
+///
+/// assert_eq!(3, policy.max_attempts);
+/// assert_eq!(Duration::from_millis(50), policy.delay(2));
+/// assert_eq!(Duration::from_millis(100), policy.delay(3));
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    pub backoff: Duration,
+    /// Factor the delay is multiplied by after each retry
+    pub backoff_factor: u32,
+    /// Whether an error is worth another attempt
+    pub is_retryable: fn(&Error) -> bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+impl RetryPolicy {
+    /// Retry transient errors for up to `max_attempts` attempts in total
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            backoff: DEFAULT_RETRY_BACKOFF,
+            backoff_factor: 2,
+            is_retryable,
+        }
+    }
+
+    /// Wait `backoff` before the first retry, multiplying it by `factor` after each retry
+    pub fn and_backoff(mut self, backoff: Duration, factor: u32) -> Self {
+        self.backoff = backoff;
+        self.backoff_factor = factor;
+        self
+    }
+
+    /// Decide which errors are retried instead of using [`is_retryable`]
+    pub fn and_retryable(mut self, is_retryable: fn(&Error) -> bool) -> Self {
+        self.is_retryable = is_retryable;
+        self
+    }
+
+    /// Delay before the given attempt, counting the first attempt as 1
+    ///
+    /// The first attempt is not delayed, the first retry waits for `backoff`.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(2);
+        self.backoff
+            .saturating_mul(self.backoff_factor.saturating_pow(exponent))
+    }
+}
+
+/// Default test for errors worth retrying
+///
+/// NACKs, timeouts and malformed responses can all be caused by a corrupted
+/// or lost byte.  Invalid arguments and other I/O errors are not retried.
+pub fn is_retryable(error: &Error) -> bool {
+    match error.root() {
+        Error::Nack(_)
+        | Error::InvalidResponse(_)
+        | Error::ResponseChecksum
+        | Error::ResponseLength { .. } => true,
+        Error::Io(e) => matches!(
+            e.kind(),
+            IoErrorKind::TimedOut | IoErrorKind::WouldBlock | IoErrorKind::Interrupted
+        ),
+        _ => false,
+    }
+}
@@ -0,0 +1,1083 @@
+//! Simulated STM32 system memory bootloader
+//!
+//! [`Simulator`] models the flash, RAM and option bytes of a device and
+//! answers the AN3155 wire protocol byte-for-byte, including ACK/NACK
+//! responses and checksum validation.  It implements [`Transport`] so it
+//! can be handed directly to [`Builder::with_transport`](crate::Builder::with_transport),
+//! or it can [`serve`](Simulator::serve) any byte stream such as a TCP
+//! connection.
+//!
+//! # Example
+//! ```
+//! # use stm32_an3155_rs::{sim::Simulator, Builder};
+//! let sim = Simulator::new();
+//! let mut an3155 = Builder::with_transport(sim).initialize().unwrap();
+//!
+//! an3155.write_memory(0x2000_1000, &[1, 2, 3, 4]).unwrap();
+//! let mut buf = [0u8; 4];
+//! an3155.read_memory(0x2000_1000, &mut buf).unwrap();
+//! assert_eq!([1, 2, 3, 4], buf);
+//! ```
+use log::{debug, trace};
+
+use crate::{
+    crc32_with_polynomial, BootPins, BootloaderCommand, ControlLine, Response, SpecialResponse,
+    Transport, MAX_EXTENDED_SPECIAL_DATA_COUNT, MAX_SPECIAL_DATA_COUNT, SYNC_BYTE,
+};
+
+pub use crate::ERASED_BYTE;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write},
+    ops::Range,
+    time::Duration,
+};
+
+/// Commands understood by the simulator, using the standard erase command
+pub const DEFAULT_COMMANDS: &[BootloaderCommand] = &[
+    BootloaderCommand::Get,
+    BootloaderCommand::GetVersion,
+    BootloaderCommand::GetId,
+    BootloaderCommand::ReadMemory,
+    BootloaderCommand::Go,
+    BootloaderCommand::WriteMemory,
+    BootloaderCommand::Erase,
+    BootloaderCommand::WriteProtect,
+    BootloaderCommand::WriteUnprotect,
+    BootloaderCommand::ReadoutProtect,
+    BootloaderCommand::ReadoutUnprotect,
+];
+
+/// Line fault applied to a byte received by the simulator
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Invert every bit of the byte
+    Corrupt,
+    /// Lose the byte entirely
+    Drop,
+}
+
+/// Special or ExtendedSpecial command received by the simulator
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpecialRequest {
+    pub opcode: u16,
+    pub data: Vec<u8>,
+    /// File data of an ExtendedSpecial command, `None` for Special commands
+    pub file_data: Option<Vec<u8>>,
+}
+
+/// Contiguous block of simulated memory
+struct Region {
+    base: u32,
+    data: Vec<u8>,
+}
+
+impl Region {
+    fn new(base: u32, size: usize, fill: u8) -> Self {
+        Self {
+            base,
+            data: vec![fill; size],
+        }
+    }
+
+    fn contains(&self, address: u32) -> bool {
+        address >= self.base && ((address - self.base) as usize) < self.data.len()
+    }
+
+    /// Offsets into `data` for `len` bytes starting at `address`
+    fn offsets(&self, address: u32, len: usize) -> Option<Range<usize>> {
+        if !self.contains(address) {
+            return None;
+        }
+        let start = (address - self.base) as usize;
+        let end = start.checked_add(len)?;
+        (end <= self.data.len()).then_some(start..end)
+    }
+}
+
+/// Which memory a frame addresses
+#[derive(Clone, Copy)]
+enum Target {
+    Flash,
+    Ram,
+    OptionBytes,
+    SystemMemory,
+}
+
+/// Position of the simulated bootloader in the protocol
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stage {
+    /// Waiting for the baudrate sync byte
+    Sync,
+    /// Waiting for a command and its complement
+    Command,
+    ReadAddress,
+    ReadCount {
+        address: u32,
+    },
+    GoAddress,
+    /// Running the application after a Go command
+    Running {
+        address: u32,
+    },
+    WriteAddress,
+    WriteData {
+        address: u32,
+    },
+    EraseList,
+    ExtendedEraseList,
+    WriteProtectList,
+    ChecksumAddress,
+    ChecksumSize {
+        address: u32,
+    },
+    ChecksumPolynomial {
+        address: u32,
+        size: u32,
+    },
+    ChecksumInitial {
+        address: u32,
+        size: u32,
+        polynomial: u32,
+    },
+    SpecialOpcode,
+    SpecialData {
+        opcode: u16,
+    },
+    ExtendedSpecialOpcode,
+    ExtendedSpecialData {
+        opcode: u16,
+    },
+    ExtendedSpecialFileData {
+        opcode: u16,
+    },
+}
+
+/// Simulated STM32 bootloader
+///
+/// The default model is loosely an STM32F103 medium density part: 128 KiB of
+/// flash in 1 KiB pages, 20 KiB of RAM and bootloader version 2.2 using the
+/// standard erase command.
+pub struct Simulator {
+    pid: u16,
+    version: u8,
+    commands: Vec<BootloaderCommand>,
+    /// Opcodes listed by Get after the known commands
+    unknown_commands: Vec<u8>,
+    /// Option bytes sent by GetVersion
+    version_options: [u8; 2],
+    flash: Region,
+    /// Offsets of each flash page into `flash.data`
+    pages: Vec<Range<usize>>,
+    ram: Region,
+    option_bytes: Region,
+    system_memory: Region,
+    read_protected: bool,
+    /// Write protection state of each flash page
+    write_protected: Vec<bool>,
+    /// Pages covered by each write protection sector, and how many sectors
+    /// there are
+    wrp_sectors: Option<(usize, usize)>,
+    /// Canned responses to Special and ExtendedSpecial opcodes
+    special: HashMap<u16, SpecialResponse>,
+    special_requests: Vec<SpecialRequest>,
+    stage: Stage,
+    /// Line faults applied to received bytes, by index of the byte
+    faults: HashMap<usize, Fault>,
+    received: usize,
+    rx: Vec<u8>,
+    tx: VecDeque<u8>,
+    baud_rate: Option<u32>,
+    /// Fastest baud rate the simulated bootloader receives reliably
+    max_baud_rate: Option<u32>,
+    /// Baud rate the bootloader measured from the sync byte
+    locked_baud_rate: Option<u32>,
+    timeout: Option<Duration>,
+    /// Levels of the DTR and RTS lines
+    dtr: bool,
+    rts: bool,
+    /// Wiring of the reset and BOOT0 pins to the control lines, if any
+    boot_pins: Option<BootPins>,
+    in_reset: bool,
+}
+
+/// Option bytes of an STM32F1 as shipped: unprotected, every value followed
+/// by its complement
+const F1_FACTORY_OPTION_BYTES: [u8; 16] = [
+    0xA5, 0x5A, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00,
+];
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        Self {
+            pid: 0x0410,
+            version: 0x22,
+            commands: DEFAULT_COMMANDS.to_vec(),
+            unknown_commands: Vec::new(),
+            version_options: [0x00, 0x00],
+            flash: Region::new(0x0800_0000, 0, ERASED_BYTE),
+            pages: Vec::new(),
+            ram: Region::new(0x2000_0000, 20 * 1024, 0x00),
+            option_bytes: Region {
+                base: 0x1FFF_F800,
+                data: F1_FACTORY_OPTION_BYTES.to_vec(),
+            },
+            system_memory: Region::new(0x1FFF_F000, 2 * 1024, 0x00),
+            read_protected: false,
+            write_protected: Vec::new(),
+            wrp_sectors: None,
+            special: HashMap::new(),
+            special_requests: Vec::new(),
+            stage: Stage::Sync,
+            faults: HashMap::new(),
+            received: 0,
+            rx: Vec::new(),
+            tx: VecDeque::new(),
+            baud_rate: None,
+            max_baud_rate: None,
+            locked_baud_rate: None,
+            timeout: None,
+            dtr: false,
+            rts: false,
+            boot_pins: None,
+            in_reset: false,
+        }
+        .and_flash(0x0800_0000, 1024, 128)
+    }
+
+    /// Set the product ID returned by GetId
+    pub fn and_pid(mut self, pid: u16) -> Self {
+        self.pid = pid;
+        self
+    }
+
+    /// Set the bootloader version returned by Get and GetVersion
+    pub fn and_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Set the commands advertised and accepted by the bootloader
+    ///
+    /// Commands not in this list are NACKed.
+    pub fn and_commands(mut self, commands: &[BootloaderCommand]) -> Self {
+        self.commands = commands.to_vec();
+        self
+    }
+
+    /// Advertise opcodes of commands the library does not know, which are NACKed
+    pub fn and_unknown_commands(mut self, opcodes: &[u8]) -> Self {
+        self.unknown_commands = opcodes.to_vec();
+        self
+    }
+
+    /// Set the option bytes sent by the GetVersion command
+    pub fn and_version_options(mut self, option_bytes: [u8; 2]) -> Self {
+        self.version_options = option_bytes;
+        self
+    }
+
+    /// Use uniformly sized flash pages
+    pub fn and_flash(self, base: u32, page_size: usize, page_count: usize) -> Self {
+        self.and_flash_sectors(base, &vec![page_size; page_count])
+    }
+
+    /// Use flash sectors of varying sizes, e.g. on STM32F4 parts
+    pub fn and_flash_sectors(mut self, base: u32, sizes: &[usize]) -> Self {
+        let mut offset = 0;
+        self.pages = sizes
+            .iter()
+            .map(|size| {
+                let page = offset..offset + size;
+                offset += size;
+                page
+            })
+            .collect();
+        self.flash = Region::new(base, offset, ERASED_BYTE);
+        self.write_protected = vec![false; sizes.len()];
+        self
+    }
+
+    /// Group flash pages into write protection sectors of `pages` pages,
+    /// at most `count` of them, with the last covering the remaining pages
+    pub fn and_wrp_sectors(mut self, pages: usize, count: usize) -> Self {
+        self.wrp_sectors = Some((pages, count));
+        self
+    }
+
+    /// Set the location and size of RAM
+    pub fn and_ram(mut self, base: u32, size: usize) -> Self {
+        self.ram = Region::new(base, size, 0x00);
+        self
+    }
+
+    /// Set the location and initial contents of the option bytes
+    pub fn and_option_bytes(mut self, base: u32, bytes: &[u8]) -> Self {
+        self.option_bytes = Region {
+            base,
+            data: bytes.to_vec(),
+        };
+        self
+    }
+
+    /// Enable or disable readout protection
+    pub fn and_read_protection(mut self, enabled: bool) -> Self {
+        self.read_protected = enabled;
+        self
+    }
+
+    /// Accept a Special or ExtendedSpecial opcode and answer it with `response`
+    ///
+    /// Opcodes without a response are NACKed.  ExtendedSpecial commands only
+    /// return the status bytes.
+    pub fn and_special(mut self, opcode: u16, response: SpecialResponse) -> Self {
+        self.special.insert(opcode, response);
+        self
+    }
+
+    /// Corrupt every byte received faster than `baud_rate`
+    pub fn and_max_baud_rate(mut self, baud_rate: u32) -> Self {
+        self.max_baud_rate = Some(baud_rate);
+        self
+    }
+
+    /// Wire the reset and BOOT0 pins to control lines
+    ///
+    /// The device starts out running the application from flash and only
+    /// enters the bootloader when reset is released with BOOT0 active.
+    pub fn and_boot_pins(mut self, pins: BootPins) -> Self {
+        self.stage = Stage::Running {
+            address: self.flash.base,
+        };
+        self.boot_pins = Some(pins);
+        self
+    }
+
+    /// Simulate line noise on a received byte
+    ///
+    /// `index` counts every byte received by the simulator, starting at zero
+    /// with the first sync byte.
+    pub fn and_fault(mut self, index: usize, fault: Fault) -> Self {
+        self.faults.insert(index, fault);
+        self
+    }
+
+    /// Preload flash contents starting at `address`
+    ///
+    /// # Panics
+    /// Panics if the data does not fit in flash.
+    pub fn and_flash_contents(mut self, address: u32, bytes: &[u8]) -> Self {
+        let range = self
+            .flash
+            .offsets(address, bytes.len())
+            .expect("contents must fit in flash");
+        self.flash.data[range].copy_from_slice(bytes);
+        self
+    }
+
+    /// Product ID
+    pub fn pid(&self) -> u16 {
+        self.pid
+    }
+
+    /// Current flash contents
+    pub fn flash(&self) -> &[u8] {
+        &self.flash.data
+    }
+
+    /// Current RAM contents
+    pub fn ram(&self) -> &[u8] {
+        &self.ram.data
+    }
+
+    /// Current option byte contents
+    pub fn option_bytes(&self) -> &[u8] {
+        &self.option_bytes.data
+    }
+
+    /// Whether readout protection is active
+    pub fn read_protected(&self) -> bool {
+        self.read_protected
+    }
+
+    /// Indices of write protected flash pages
+    pub fn write_protected_pages(&self) -> Vec<usize> {
+        (0..self.pages.len())
+            .filter(|&p| self.write_protected[p])
+            .collect()
+    }
+
+    /// Special and ExtendedSpecial commands received so far
+    pub fn special_requests(&self) -> &[SpecialRequest] {
+        &self.special_requests
+    }
+
+    /// Whether the host has synchronized with the bootloader
+    pub fn is_synced(&self) -> bool {
+        !matches!(self.stage, Stage::Sync | Stage::Running { .. })
+    }
+
+    /// Address the application was started from, if a Go command was accepted
+    pub fn application_address(&self) -> Option<u32> {
+        match self.stage {
+            Stage::Running { address } => Some(address),
+            _ => None,
+        }
+    }
+
+    /// Last baud rate set through [`Transport::set_baud`]
+    pub fn baud_rate(&self) -> Option<u32> {
+        self.baud_rate
+    }
+
+    /// Last timeout set through [`Transport::set_timeout`]
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Read simulated memory at any mapped address
+    pub fn memory(&self, address: u32, len: usize) -> Option<&[u8]> {
+        let (target, range) = self.locate(address, len)?;
+        Some(&self.region(target).data[range])
+    }
+
+    /// Answer the bootloader protocol on a byte stream until it is closed
+    pub fn serve<S: Read + Write>(&mut self, mut stream: S) -> IoResult<()> {
+        let mut buf = [0u8; 512];
+        loop {
+            let n = match stream.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(e) if e.kind() == IoErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == IoErrorKind::ConnectionReset => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            self.receive(&buf[..n]);
+            let response: Vec<u8> = self.tx.drain(..).collect();
+            stream.write_all(&response)?;
+            stream.flush()?;
+        }
+    }
+
+    /// Level of a control line set through [`Transport::set_control_line`]
+    pub fn control_line(&self, line: ControlLine) -> bool {
+        match line {
+            ControlLine::Dtr => self.dtr,
+            ControlLine::Rts => self.rts,
+        }
+    }
+
+    /// Reset the device back into the bootloader
+    fn reset(&mut self) {
+        debug!("simulator: system reset");
+        self.stage = Stage::Sync;
+        self.locked_baud_rate = None;
+        self.rx.clear();
+    }
+
+    /// Whether bytes arrive intact at the current baud rate
+    fn baud_rate_ok(&self) -> bool {
+        let too_fast =
+            matches!((self.baud_rate, self.max_baud_rate), (Some(rate), Some(max)) if rate > max);
+        let locked = self.locked_baud_rate.is_some() && self.locked_baud_rate != self.baud_rate;
+        !too_fast && !locked
+    }
+
+    fn region(&self, target: Target) -> &Region {
+        match target {
+            Target::Flash => &self.flash,
+            Target::Ram => &self.ram,
+            Target::OptionBytes => &self.option_bytes,
+            Target::SystemMemory => &self.system_memory,
+        }
+    }
+
+    fn region_mut(&mut self, target: Target) -> &mut Region {
+        match target {
+            Target::Flash => &mut self.flash,
+            Target::Ram => &mut self.ram,
+            Target::OptionBytes => &mut self.option_bytes,
+            Target::SystemMemory => &mut self.system_memory,
+        }
+    }
+
+    fn locate(&self, address: u32, len: usize) -> Option<(Target, Range<usize>)> {
+        [
+            Target::Flash,
+            Target::Ram,
+            Target::OptionBytes,
+            Target::SystemMemory,
+        ]
+        .into_iter()
+        .find_map(|target| {
+            self.region(target)
+                .offsets(address, len)
+                .map(|range| (target, range))
+        })
+    }
+
+    fn is_mapped(&self, address: u32) -> bool {
+        self.locate(address, 1).is_some()
+    }
+
+    fn send(&mut self, bytes: &[u8]) {
+        trace!("simulator: sending {:02X?}", bytes);
+        self.tx.extend(bytes);
+    }
+
+    fn send_with_length(&mut self, bytes: &[u8]) {
+        self.send(&(bytes.len() as u16).to_be_bytes());
+        self.send(bytes);
+    }
+
+    fn ack(&mut self) {
+        self.send(&[Response::Ack as u8]);
+    }
+
+    fn nack(&mut self) {
+        self.send(&[Response::Nack as u8]);
+    }
+
+    /// Number of bytes the current stage needs before it can be processed
+    fn needed(&self) -> usize {
+        match self.stage {
+            Stage::Sync | Stage::Running { .. } => 1,
+            Stage::Command | Stage::ReadCount { .. } => 2,
+            Stage::SpecialOpcode | Stage::ExtendedSpecialOpcode => 3,
+            Stage::SpecialData { .. }
+            | Stage::ExtendedSpecialData { .. }
+            | Stage::ExtendedSpecialFileData { .. } => match self.rx.get(..2) {
+                Some(&[hi, lo]) => u16::from_be_bytes([hi, lo]) as usize + 3,
+                _ => 2,
+            },
+            Stage::ReadAddress
+            | Stage::GoAddress
+            | Stage::WriteAddress
+            | Stage::ChecksumAddress
+            | Stage::ChecksumSize { .. }
+            | Stage::ChecksumPolynomial { .. }
+            | Stage::ChecksumInitial { .. } => 5,
+            Stage::WriteData { .. } => match self.rx.first() {
+                Some(&n) => n as usize + 3,
+                None => 1,
+            },
+            Stage::EraseList => match self.rx.first() {
+                Some(0xFF) => 2,
+                Some(&n) => n as usize + 3,
+                None => 1,
+            },
+            Stage::WriteProtectList => match self.rx.first() {
+                Some(&n) => n as usize + 3,
+                None => 1,
+            },
+            Stage::ExtendedEraseList => match self.rx.get(..2) {
+                Some(&[hi, lo]) => match u16::from_be_bytes([hi, lo]) {
+                    n if n >= 0xFFF0 => 3,
+                    n => 2 * (n as usize + 1) + 3,
+                },
+                _ => 2,
+            },
+        }
+    }
+
+    fn receive(&mut self, bytes: &[u8]) {
+        trace!("simulator: received {:02X?}", bytes);
+        for &b in bytes {
+            let index = self.received;
+            self.received += 1;
+            match self.faults.remove(&index) {
+                Some(Fault::Corrupt) => {
+                    debug!("simulator: corrupting byte #{index}");
+                    self.rx.push(!b);
+                }
+                Some(Fault::Drop) => {
+                    debug!("simulator: dropping byte #{index}");
+                    continue;
+                }
+                None if !self.baud_rate_ok() => {
+                    debug!(
+                        "simulator: garbling byte #{index} at {:?} baud",
+                        self.baud_rate
+                    );
+                    self.rx.push(!b);
+                }
+                None => self.rx.push(b),
+            }
+            while !self.rx.is_empty() && self.rx.len() >= self.needed() {
+                let needed = self.needed();
+                let frame: Vec<u8> = self.rx.drain(..needed).collect();
+                self.process(&frame);
+            }
+        }
+    }
+
+    fn process(&mut self, frame: &[u8]) {
+        match self.stage {
+            Stage::Sync => {
+                if frame[0] == SYNC_BYTE {
+                    debug!("simulator: synchronized");
+                    self.locked_baud_rate = self.baud_rate;
+                    self.ack();
+                    self.stage = Stage::Command;
+                }
+            }
+            Stage::Command => self.process_command(frame[0], frame[1]),
+            Stage::ReadAddress => {
+                self.stage = Stage::Command;
+                if let Some(address) = self.parse_address(frame) {
+                    self.ack();
+                    self.stage = Stage::ReadCount { address };
+                }
+            }
+            Stage::ReadCount { address } => {
+                self.stage = Stage::Command;
+                let (n, complement) = (frame[0], frame[1]);
+                let len = n as usize + 1;
+                match self.locate(address, len) {
+                    Some((target, range)) if n == !complement => {
+                        self.ack();
+                        let data = self.region(target).data[range].to_vec();
+                        self.send(&data);
+                    }
+                    _ => self.nack(),
+                }
+            }
+            Stage::GoAddress => {
+                self.stage = Stage::Command;
+                let address = match self.parse_address(frame) {
+                    Some(address) => address,
+                    None => return,
+                };
+                match self.locate(address, 8) {
+                    Some((Target::Flash | Target::Ram, _)) => {
+                        debug!("simulator: jumping to {address:08X}");
+                        self.ack();
+                        self.stage = Stage::Running { address };
+                    }
+                    _ => self.nack(),
+                }
+            }
+            // the application ignores anything sent to the bootloader
+            Stage::Running { .. } => (),
+            Stage::WriteAddress => {
+                self.stage = Stage::Command;
+                if let Some(address) = self.parse_address(frame) {
+                    self.ack();
+                    self.stage = Stage::WriteData { address };
+                }
+            }
+            Stage::WriteData { address } => {
+                self.stage = Stage::Command;
+                let data = &frame[1..frame.len() - 1];
+                if !checksum_ok(frame) {
+                    return self.nack();
+                }
+                match self.locate(address, data.len()) {
+                    Some((Target::Flash, range)) if self.is_write_protected(&range) => self.nack(),
+                    Some((Target::Flash, range)) => {
+                        // flash bits can only be cleared by writing
+                        self.flash.data[range]
+                            .iter_mut()
+                            .zip(data)
+                            .for_each(|(cell, b)| *cell &= b);
+                        self.ack();
+                    }
+                    Some((Target::SystemMemory, _)) | None => self.nack(),
+                    Some((Target::OptionBytes, range)) => {
+                        // the device resets to load new option bytes
+                        self.option_bytes.data[range].copy_from_slice(data);
+                        self.ack();
+                        self.reset();
+                    }
+                    Some((target, range)) => {
+                        self.region_mut(target).data[range].copy_from_slice(data);
+                        self.ack();
+                    }
+                }
+            }
+            Stage::ChecksumAddress => {
+                self.stage = Stage::Command;
+                if let Some(address) = self.parse_address(frame) {
+                    self.ack();
+                    self.stage = Stage::ChecksumSize { address };
+                }
+            }
+            Stage::ChecksumSize { address } => {
+                self.stage = Stage::Command;
+                let size = parse_word(frame);
+                match size {
+                    Some(size)
+                        if size.is_multiple_of(4)
+                            && self.locate(address, size as usize).is_some() =>
+                    {
+                        self.ack();
+                        self.stage = Stage::ChecksumPolynomial { address, size };
+                    }
+                    _ => self.nack(),
+                }
+            }
+            Stage::ChecksumPolynomial { address, size } => {
+                self.stage = Stage::Command;
+                if let Some(polynomial) = parse_word(frame) {
+                    self.ack();
+                    self.stage = Stage::ChecksumInitial {
+                        address,
+                        size,
+                        polynomial,
+                    };
+                } else {
+                    self.nack();
+                }
+            }
+            Stage::ChecksumInitial {
+                address,
+                size,
+                polynomial,
+            } => {
+                self.stage = Stage::Command;
+                let initial = match parse_word(frame) {
+                    Some(initial) => initial,
+                    None => return self.nack(),
+                };
+                let data = self
+                    .memory(address, size as usize)
+                    .expect("checksum size was validated");
+                let crc = crc32_with_polynomial(polynomial, initial, data).to_be_bytes();
+                let checksum = crc.iter().fold(0u8, |acc, b| acc ^ b);
+                self.ack();
+                self.send(&crc);
+                self.send(&[checksum]);
+            }
+            Stage::SpecialOpcode | Stage::ExtendedSpecialOpcode => {
+                let extended = self.stage == Stage::ExtendedSpecialOpcode;
+                self.stage = Stage::Command;
+                let opcode = u16::from_be_bytes([frame[0], frame[1]]);
+                if !checksum_ok(frame) || !self.special.contains_key(&opcode) {
+                    debug!("simulator: rejecting special opcode {opcode:04X}");
+                    return self.nack();
+                }
+                self.ack();
+                self.stage = match extended {
+                    false => Stage::SpecialData { opcode },
+                    true => Stage::ExtendedSpecialData { opcode },
+                };
+            }
+            Stage::SpecialData { opcode } => {
+                self.stage = Stage::Command;
+                let data = &frame[2..frame.len() - 1];
+                if !checksum_ok(frame) || data.len() > MAX_SPECIAL_DATA_COUNT {
+                    return self.nack();
+                }
+                self.special_requests.push(SpecialRequest {
+                    opcode,
+                    data: data.to_vec(),
+                    file_data: None,
+                });
+                let response = self.special[&opcode].clone();
+                self.ack();
+                self.send_with_length(&response.data);
+                self.send_with_length(&response.status);
+                self.ack();
+            }
+            Stage::ExtendedSpecialData { opcode } => {
+                self.stage = Stage::Command;
+                let data = &frame[2..frame.len() - 1];
+                if !checksum_ok(frame) || data.len() > MAX_SPECIAL_DATA_COUNT {
+                    return self.nack();
+                }
+                self.special_requests.push(SpecialRequest {
+                    opcode,
+                    data: data.to_vec(),
+                    file_data: None,
+                });
+                self.ack();
+                self.stage = Stage::ExtendedSpecialFileData { opcode };
+            }
+            Stage::ExtendedSpecialFileData { opcode } => {
+                self.stage = Stage::Command;
+                let file_data = &frame[2..frame.len() - 1];
+                if !checksum_ok(frame) || file_data.len() > MAX_EXTENDED_SPECIAL_DATA_COUNT {
+                    self.special_requests.pop();
+                    return self.nack();
+                }
+                if let Some(request) = self.special_requests.last_mut() {
+                    request.file_data = Some(file_data.to_vec());
+                }
+                let status = self.special[&opcode].status.clone();
+                self.ack();
+                self.send_with_length(&status);
+                self.ack();
+            }
+            Stage::WriteProtectList => {
+                self.stage = Stage::Command;
+                let sectors = &frame[1..frame.len() - 1];
+                let (group, count) = self.wrp_sectors.unwrap_or((1, self.pages.len()));
+                let count = count.min(self.pages.len().div_ceil(group));
+                if !checksum_ok(frame) || sectors.iter().any(|&s| s as usize >= count) {
+                    return self.nack();
+                }
+                for &sector in sectors {
+                    let start = sector as usize * group;
+                    let end = match sector as usize + 1 == count {
+                        true => self.pages.len(),
+                        false => start + group,
+                    };
+                    debug!("simulator: write protecting pages {start}..{end}");
+                    self.write_protected[start..end].fill(true);
+                }
+                self.ack();
+                self.reset();
+            }
+            Stage::EraseList => {
+                self.stage = Stage::Command;
+                if frame == [0xFF, 0x00] {
+                    self.erase_all();
+                    return self.ack();
+                }
+                if !checksum_ok(frame) {
+                    return self.nack();
+                }
+                let pages: Vec<usize> = frame[1..frame.len() - 1]
+                    .iter()
+                    .map(|&p| p as usize)
+                    .collect();
+                self.erase_pages(&pages);
+            }
+            Stage::ExtendedEraseList => {
+                self.stage = Stage::Command;
+                if !checksum_ok(frame) {
+                    return self.nack();
+                }
+                let half = self.pages.len() / 2;
+                match u16::from_be_bytes([frame[0], frame[1]]) {
+                    0xFFFF => {
+                        self.erase_all();
+                        self.ack();
+                    }
+                    0xFFFE => self.erase_pages(&(0..half).collect::<Vec<_>>()),
+                    0xFFFD => self.erase_pages(&(half..self.pages.len()).collect::<Vec<_>>()),
+                    n if n >= 0xFFF0 => self.nack(),
+                    _ => {
+                        let pages: Vec<usize> = frame[2..frame.len() - 1]
+                            .chunks(2)
+                            .map(|c| u16::from_be_bytes([c[0], c[1]]) as usize)
+                            .collect();
+                        self.erase_pages(&pages);
+                    }
+                }
+            }
+        }
+    }
+
+    fn process_command(&mut self, command: u8, complement: u8) {
+        let command = match BootloaderCommand::try_from(command) {
+            Ok(c) if command == !complement && self.commands.contains(&c) => c,
+            _ => {
+                debug!("simulator: rejecting command {command:02X} {complement:02X}");
+                return self.nack();
+            }
+        };
+        debug!("simulator: received command {:?}", command);
+
+        match command {
+            BootloaderCommand::Get => {
+                self.ack();
+                let count = self.commands.len() + self.unknown_commands.len();
+                let mut buf = vec![count as u8, self.version];
+                buf.extend(self.commands.iter().map(|&c| c as u8));
+                buf.extend(&self.unknown_commands);
+                self.send(&buf);
+                self.ack();
+            }
+            BootloaderCommand::GetVersion => {
+                self.ack();
+                let [first, second] = self.version_options;
+                self.send(&[self.version, first, second]);
+                self.ack();
+            }
+            BootloaderCommand::GetId => {
+                self.ack();
+                let [hi, lo] = self.pid.to_be_bytes();
+                self.send(&[0x01, hi, lo]);
+                self.ack();
+            }
+            BootloaderCommand::ReadMemory if !self.read_protected => {
+                self.ack();
+                self.stage = Stage::ReadAddress;
+            }
+            BootloaderCommand::Go if !self.read_protected => {
+                self.ack();
+                self.stage = Stage::GoAddress;
+            }
+            BootloaderCommand::WriteMemory if !self.read_protected => {
+                self.ack();
+                self.stage = Stage::WriteAddress;
+            }
+            BootloaderCommand::Erase if !self.read_protected => {
+                self.ack();
+                self.stage = Stage::EraseList;
+            }
+            BootloaderCommand::ExtendedErase if !self.read_protected => {
+                self.ack();
+                self.stage = Stage::ExtendedEraseList;
+            }
+            BootloaderCommand::GetChecksum if !self.read_protected => {
+                self.ack();
+                self.stage = Stage::ChecksumAddress;
+            }
+            BootloaderCommand::Special => {
+                self.ack();
+                self.stage = Stage::SpecialOpcode;
+            }
+            BootloaderCommand::ExtendedSpecial => {
+                self.ack();
+                self.stage = Stage::ExtendedSpecialOpcode;
+            }
+            BootloaderCommand::WriteProtect if !self.read_protected => {
+                self.ack();
+                self.stage = Stage::WriteProtectList;
+            }
+            BootloaderCommand::WriteUnprotect if !self.read_protected => {
+                self.ack();
+                self.write_protected.fill(false);
+                self.ack();
+                self.reset();
+            }
+            BootloaderCommand::ReadoutProtect if !self.read_protected => {
+                self.ack();
+                self.read_protected = true;
+                self.ack();
+                self.reset();
+            }
+            BootloaderCommand::ReadoutUnprotect => {
+                self.ack();
+                self.erase_all();
+                self.read_protected = false;
+                self.ack();
+                self.reset();
+            }
+            _ => self.nack(),
+        }
+    }
+
+    /// Validate a 4-byte address frame, sending a NACK if it is invalid
+    fn parse_address(&mut self, frame: &[u8]) -> Option<u32> {
+        let address = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        if !checksum_ok(frame) || !self.is_mapped(address) {
+            debug!("simulator: rejecting address {address:08X}");
+            self.nack();
+            return None;
+        }
+        Some(address)
+    }
+
+    /// Whether any page overlapping `range` is write protected
+    fn is_write_protected(&self, range: &Range<usize>) -> bool {
+        self.pages
+            .iter()
+            .zip(&self.write_protected)
+            .any(|(page, &protected)| protected && page.start < range.end && range.start < page.end)
+    }
+
+    fn erase_all(&mut self) {
+        debug!("simulator: mass erase");
+        self.flash.data.fill(ERASED_BYTE);
+    }
+
+    fn erase_pages(&mut self, pages: &[usize]) {
+        if pages
+            .iter()
+            .any(|&p| p >= self.pages.len() || self.write_protected[p])
+        {
+            return self.nack();
+        }
+        for &page in pages {
+            debug!("simulator: erasing page {page}");
+            let range = self.pages[page].clone();
+            self.flash.data[range].fill(ERASED_BYTE);
+        }
+        self.ack();
+    }
+}
+
+/// Parse a 4-byte big endian word followed by its checksum
+fn parse_word(frame: &[u8]) -> Option<u32> {
+    checksum_ok(frame).then(|| u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]))
+}
+
+/// Check that the XOR of all bytes in a frame, including its trailing checksum, is zero
+fn checksum_ok(frame: &[u8]) -> bool {
+    frame.iter().fold(0u8, |acc, b| acc ^ b) == 0
+}
+
+impl Transport for Simulator {
+    fn read_exact(&mut self, buf: &mut [u8]) -> IoResult<()> {
+        if self.tx.len() < buf.len() {
+            // a real port would deliver what it has and then time out
+            self.tx.clear();
+            return Err(IoError::from(IoErrorKind::TimedOut));
+        }
+        let len = buf.len();
+        for (dst, src) in buf.iter_mut().zip(self.tx.drain(..len)) {
+            *dst = src;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.receive(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+
+    fn discard_input(&mut self) -> IoResult<()> {
+        self.tx.clear();
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> IoResult<()> {
+        self.timeout.replace(timeout);
+        Ok(())
+    }
+
+    fn set_baud(&mut self, baud_rate: u32) -> IoResult<()> {
+        self.baud_rate.replace(baud_rate);
+        Ok(())
+    }
+
+    fn set_control_line(&mut self, line: ControlLine, level: bool) -> IoResult<()> {
+        match line {
+            ControlLine::Dtr => self.dtr = level,
+            ControlLine::Rts => self.rts = level,
+        }
+        let Some(pins) = &self.boot_pins else {
+            return Ok(());
+        };
+        let in_reset = self.control_line(pins.reset) == pins.reset_level(true);
+        let boot0 = self.control_line(pins.boot0) == pins.boot0_level(true);
+        if self.in_reset && !in_reset {
+            match boot0 {
+                true => self.reset(),
+                false => {
+                    debug!("simulator: booting from flash");
+                    self.stage = Stage::Running {
+                        address: self.flash.base,
+                    };
+                }
+            }
+        }
+        self.in_reset = in_reset;
+        Ok(())
+    }
+}
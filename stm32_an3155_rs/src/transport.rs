@@ -0,0 +1,193 @@
+use crate::ControlLine;
+use log::debug;
+
+use std::{
+    io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+/// Byte transport used to talk to the bootloader
+///
+/// The bootloader protocol only needs a handful of operations from the
+/// underlying link.  Implement this trait to drive [`AN3155`](crate::AN3155)
+/// over something other than a local serial port, e.g. a TCP-to-UART bridge,
+/// a PTY or an in-memory test fixture.
+pub trait Transport {
+    /// Read exactly `buf.len()` bytes, failing if they do not arrive in time
+    fn read_exact(&mut self, buf: &mut [u8]) -> IoResult<()>;
+
+    /// Write bytes to the link, returning the number of bytes written
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize>;
+
+    /// Flush any buffered output to the link
+    fn flush(&mut self) -> IoResult<()>;
+
+    /// Drop any received bytes that have not been read yet
+    ///
+    /// Does nothing by default, for transports that cannot hold stale input.
+    fn discard_input(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+
+    /// Set the timeout used for reads and writes
+    fn set_timeout(&mut self, timeout: Duration) -> IoResult<()>;
+
+    /// Set the link baud rate
+    ///
+    /// Transports without a notion of baud rate should accept any value.
+    fn set_baud(&mut self, baud_rate: u32) -> IoResult<()>;
+
+    /// Assert or deassert a modem control line
+    ///
+    /// Fails with [`ErrorKind::Unsupported`] by default, for transports
+    /// without control lines.
+    fn set_control_line(&mut self, line: ControlLine, _level: bool) -> IoResult<()> {
+        Err(IoError::new(
+            ErrorKind::Unsupported,
+            format!("cannot drive {line:?} over this transport"),
+        ))
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn read_exact(&mut self, buf: &mut [u8]) -> IoResult<()> {
+        (**self).read_exact(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        (**self).write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        (**self).flush()
+    }
+
+    fn discard_input(&mut self) -> IoResult<()> {
+        (**self).discard_input()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> IoResult<()> {
+        (**self).set_timeout(timeout)
+    }
+
+    fn set_baud(&mut self, baud_rate: u32) -> IoResult<()> {
+        (**self).set_baud(baud_rate)
+    }
+
+    fn set_control_line(&mut self, line: ControlLine, level: bool) -> IoResult<()> {
+        (**self).set_control_line(line, level)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> IoResult<()> {
+        (**self).read_exact(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        (**self).write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        (**self).flush()
+    }
+
+    fn discard_input(&mut self) -> IoResult<()> {
+        (**self).discard_input()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> IoResult<()> {
+        (**self).set_timeout(timeout)
+    }
+
+    fn set_baud(&mut self, baud_rate: u32) -> IoResult<()> {
+        (**self).set_baud(baud_rate)
+    }
+
+    fn set_control_line(&mut self, line: ControlLine, level: bool) -> IoResult<()> {
+        (**self).set_control_line(line, level)
+    }
+}
+
+impl Transport for dyn serialport::SerialPort {
+    fn read_exact(&mut self, buf: &mut [u8]) -> IoResult<()> {
+        Read::read_exact(self, buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        Write::write(self, buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Write::flush(self)
+    }
+
+    fn discard_input(&mut self) -> IoResult<()> {
+        self.clear(serialport::ClearBuffer::Input)
+            .map_err(Into::into)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> IoResult<()> {
+        serialport::SerialPort::set_timeout(self, timeout).map_err(Into::into)
+    }
+
+    fn set_baud(&mut self, baud_rate: u32) -> IoResult<()> {
+        self.set_baud_rate(baud_rate).map_err(Into::into)
+    }
+
+    fn set_control_line(&mut self, line: ControlLine, level: bool) -> IoResult<()> {
+        match line {
+            ControlLine::Dtr => self.write_data_terminal_ready(level),
+            ControlLine::Rts => self.write_request_to_send(level),
+        }
+        .map_err(Into::into)
+    }
+}
+
+/// TCP transport, e.g. for a networked serial bridge
+///
+/// The baud rate is configured on the remote end of the bridge, so
+/// [`Transport::set_baud`] does nothing.  Control lines are not available.
+impl Transport for TcpStream {
+    fn read_exact(&mut self, buf: &mut [u8]) -> IoResult<()> {
+        // Unix reports a socket read timeout as EAGAIN rather than TimedOut
+        Read::read_exact(self, buf).map_err(|e| match e.kind() {
+            ErrorKind::WouldBlock => IoError::new(ErrorKind::TimedOut, e),
+            _ => e,
+        })
+    }
+
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        Write::write(self, buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Write::flush(self)
+    }
+
+    fn discard_input(&mut self) -> IoResult<()> {
+        self.set_nonblocking(true)?;
+        let mut buf = [0u8; 256];
+        let result = loop {
+            match Read::read(self, &mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(n) => debug!("discarding {n} bytes of input"),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.set_nonblocking(false)?;
+        result
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> IoResult<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+
+    fn set_baud(&mut self, baud_rate: u32) -> IoResult<()> {
+        debug!("ignoring baud rate {baud_rate} on TCP transport");
+        Ok(())
+    }
+}
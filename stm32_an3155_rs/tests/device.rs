@@ -0,0 +1,89 @@
+use stm32_an3155_rs::{Device, DEVICES};
+
+#[test]
+fn product_ids_are_unique() {
+    for (i, device) in DEVICES.iter().enumerate() {
+        assert!(
+            DEVICES[i + 1..].iter().all(|d| d.pid != device.pid),
+            "duplicate PID 0x{:04X}",
+            device.pid
+        );
+    }
+}
+
+#[test]
+fn memory_maps_are_consistent() {
+    for device in DEVICES {
+        assert_eq!(0x0800_0000, device.flash.base(), "{}", device.name);
+        assert!(device.flash.sector_count() > 0, "{}", device.name);
+        assert!(!device.ram.is_empty(), "{}", device.name);
+        assert!(device.banks == 1 || device.flash.sector_count() % 2 == 0);
+    }
+}
+
+#[test]
+fn f4_sectors() {
+    let device = Device::from_pid(0x0419).unwrap();
+
+    assert_eq!(2, device.banks);
+    assert_eq!(24, device.flash.sector_count());
+    assert_eq!(Some(0x0810_0000..0x0810_4000), device.flash.sector(12));
+    assert_eq!(
+        Some(5..7),
+        device.flash.sectors_in_range(0x0802_0000, 0x2_0001)
+    );
+}
+
+#[test]
+fn unknown_pid() {
+    assert_eq!(None, Device::from_pid(0xFFFF));
+}
+
+#[test]
+fn option_byte_layouts_are_consistent() {
+    for device in DEVICES {
+        let Some(layout) = device.option_layout else {
+            continue;
+        };
+        assert!([1, 2, 4].contains(&layout.width), "{}", device.name);
+        for field in layout.fields {
+            assert!(field.value < layout.count, "{} {}", device.name, field.name);
+            assert!(field.bits > 0, "{} {}", device.name, field.name);
+            assert!(
+                field.shift + field.bits <= 8 * layout.width as u32,
+                "{} {}",
+                device.name,
+                field.name
+            );
+        }
+        assert!(layout.field("RDP").is_some(), "{}", device.name);
+    }
+}
+
+#[test]
+fn option_byte_layout_by_family() {
+    let f4 = Device::from_pid(0x0413).unwrap().option_layout.unwrap();
+    assert_eq!(16, f4.size());
+    assert!(!f4.complement);
+
+    let g0 = Device::from_pid(0x0460).unwrap().option_layout.unwrap();
+    assert_eq!(8, g0.size());
+
+    assert_eq!(None, Device::from_pid(0x0449).unwrap().option_layout);
+}
+
+#[test]
+fn write_protection_sectors() {
+    let f1 = Device::from_pid(0x0410).unwrap();
+    assert_eq!(32, f1.flash.wrp_sector_count());
+    assert_eq!(Some(4..8), f1.flash.wrp_sector_pages(1));
+
+    let f4 = Device::from_pid(0x0413).unwrap();
+    assert_eq!(f4.flash.sector_count(), f4.flash.wrp_sector_count());
+
+    for device in DEVICES {
+        let count = device.flash.wrp_sector_count();
+        let last = device.flash.wrp_sector_pages(count - 1).unwrap();
+        assert_eq!(device.flash.sector_count(), last.end, "{}", device.name);
+    }
+}
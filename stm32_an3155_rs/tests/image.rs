@@ -0,0 +1,238 @@
+use stm32_an3155_rs::{Error, Image, Segment};
+
+const EOF: &str = ":00000001FF\n";
+
+fn ihex(records: &[&str]) -> String {
+    let mut text = records.join("\n");
+    text.push('\n');
+    text.push_str(EOF);
+    text
+}
+
+#[test]
+fn ihex_segments_and_entry_point() {
+    let text = ihex(&[
+        ":020000040800F2",
+        ":0400000001020304F2",
+        ":020800000909E4",
+        ":04000005080000C12E",
+    ]);
+    let image = Image::from_ihex(&text).unwrap();
+
+    assert_eq!(
+        vec![
+            Segment {
+                address: 0x0800_0000,
+                data: vec![1, 2, 3, 4]
+            },
+            Segment {
+                address: 0x0800_0800,
+                data: vec![9, 9]
+            },
+        ],
+        image.segments
+    );
+    assert_eq!(Some(0x0800_00C1), image.entry);
+    assert_eq!(6, image.size());
+}
+
+#[test]
+fn ihex_extended_segment_address() {
+    let text = ihex(&[":020000021000EC", ":02001000AABB89", ":0400000312340005AE"]);
+    let image = Image::from_ihex(&text).unwrap();
+
+    assert_eq!(0x1_0010, image.segments[0].address);
+    assert_eq!(Some(0x1_2345), image.entry);
+}
+
+#[test]
+fn ihex_rejects_bad_checksum() {
+    let err = Image::from_ihex(&ihex(&[":0400000001020304F3"])).unwrap_err();
+    assert!(matches!(err, Error::InvalidImage(msg) if msg.contains("line 1")));
+}
+
+#[test]
+fn ihex_rejects_overlapping_data() {
+    let text = ihex(&[":0400000001020304F2", ":020002000707EE"]);
+    assert!(Image::from_ihex(&text).is_err());
+}
+
+#[test]
+fn ihex_requires_end_of_file() {
+    assert!(Image::from_ihex(":0400000001020304F2\n").is_err());
+}
+
+/// Program header: type, virtual address, physical address, file data, memory size
+type ProgramHeader<'a> = (u32, u32, u32, &'a [u8], u32);
+
+/// Build a minimal ELF32 little endian file
+fn elf(entry: u32, headers: &[ProgramHeader]) -> Vec<u8> {
+    let phoff = 52u32;
+    let mut data_offset = phoff + 32 * headers.len() as u32;
+    let mut file = b"\x7FELF\x01\x01\x01".to_vec();
+    file.resize(16, 0);
+    file.extend(2u16.to_le_bytes()); // e_type
+    file.extend(40u16.to_le_bytes()); // e_machine
+    file.extend(1u32.to_le_bytes()); // e_version
+    file.extend(entry.to_le_bytes());
+    file.extend(phoff.to_le_bytes());
+    file.extend(0u32.to_le_bytes()); // e_shoff
+    file.extend(0u32.to_le_bytes()); // e_flags
+    file.extend(52u16.to_le_bytes());
+    file.extend(32u16.to_le_bytes());
+    file.extend((headers.len() as u16).to_le_bytes());
+    file.extend([0u8; 6]);
+    for &(p_type, vaddr, paddr, data, memsz) in headers {
+        for word in [
+            p_type,
+            data_offset,
+            vaddr,
+            paddr,
+            data.len() as u32,
+            memsz,
+            0,
+            4,
+        ] {
+            file.extend(word.to_le_bytes());
+        }
+        data_offset += data.len() as u32;
+    }
+    for &(_, _, _, data, _) in headers {
+        file.extend(data);
+    }
+    file
+}
+
+#[test]
+fn elf_load_segments() {
+    let file = elf(
+        0x0800_0009,
+        &[
+            (1, 0x0800_0000, 0x0800_0000, &[1, 2, 3, 4, 5, 6, 7, 8], 8),
+            // .data: linked in RAM, loaded after .text
+            (1, 0x2000_0000, 0x0800_0008, &[9, 10, 11, 12], 16),
+            // .bss: nothing to load
+            (1, 0x2000_0010, 0x0800_000C, &[], 64),
+            // PT_NOTE
+            (4, 0, 0, &[0xEE; 4], 4),
+        ],
+    );
+    let image = Image::from_elf(&file).unwrap();
+
+    assert_eq!(
+        vec![Segment {
+            address: 0x0800_0000,
+            data: (1..=12).collect()
+        }],
+        image.segments
+    );
+    assert_eq!(Some(0x0800_0009), image.entry);
+}
+
+#[test]
+fn elf_rejects_64_bit() {
+    let mut file = elf(0, &[]);
+    file[4] = 2;
+    assert!(Image::from_elf(&file).is_err());
+}
+
+#[test]
+fn elf_rejects_truncated_segment() {
+    let mut file = elf(0, &[(1, 0x0800_0000, 0x0800_0000, &[1, 2, 3, 4], 4)]);
+    file.truncate(file.len() - 1);
+    assert!(Image::from_elf(&file).is_err());
+}
+
+#[test]
+fn srec_segments_and_entry_point() {
+    let text = "S005000066771D\n\
+                S3090800000001020304E4\n\
+                S2060010010506DD\n\
+                S1041000AA41\n\
+                S70508000101F0\n";
+    let image = Image::from_srec(text).unwrap();
+
+    assert_eq!(
+        vec![
+            Segment {
+                address: 0x1000,
+                data: vec![0xAA, 5, 6]
+            },
+            Segment {
+                address: 0x0800_0000,
+                data: vec![1, 2, 3, 4]
+            },
+        ],
+        image.segments
+    );
+    assert_eq!(Some(0x0800_0101), image.entry);
+}
+
+#[test]
+fn vector_table_is_found_from_entry_point() {
+    let vectors = |reset: u32| {
+        [0x2000_5000u32, reset]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect()
+    };
+    let blocks = [
+        Segment {
+            address: 0x0800_0000,
+            data: vectors(0x0800_0201),
+        },
+        Segment {
+            address: 0x0800_4000,
+            data: vectors(0x0800_4201),
+        },
+    ];
+    let mut image = Image::from_blocks(blocks, Some(0x0800_4201)).unwrap();
+    assert_eq!(Some(0x0800_4000), image.vector_table());
+
+    // Without a matching reset vector, Go starts from the lowest segment
+    image.entry = Some(0x0800_4203);
+    assert_eq!(Some(0x0800_0000), image.vector_table());
+    image.entry = None;
+    assert_eq!(Some(0x0800_0000), image.vector_table());
+    assert_eq!(None, Image::default().vector_table());
+}
+
+#[test]
+fn srec_zero_start_address_is_not_an_entry_point() {
+    let image = Image::from_srec("S1041000AA41\nS9030000FC\n").unwrap();
+    assert_eq!(None, image.entry);
+}
+
+#[test]
+fn srec_rejects_bad_checksum() {
+    let err = Image::from_srec("S1041000AA42\nS9030000FC\n").unwrap_err();
+    assert!(matches!(err, Error::InvalidImage(msg) if msg.contains("line 1")));
+}
+
+#[test]
+fn srec_requires_termination_record() {
+    assert!(Image::from_srec("S1041000AA41\n").is_err());
+}
+
+#[test]
+fn ihex_round_trip() {
+    let image = Image::from_blocks(
+        [
+            Segment {
+                address: 0x0800_FFF8,
+                data: (0..40).collect(),
+            },
+            Segment {
+                address: 0x2000_0000,
+                data: vec![0xAA; 3],
+            },
+        ],
+        Some(0x0800_0101),
+    )
+    .unwrap();
+    let text = image.to_ihex();
+
+    assert!(text.starts_with(":020000040800F2\n"));
+    assert!(text.ends_with(":0400000508000101ED\n:00000001FF\n"));
+    assert_eq!(image, Image::from_ihex(&text).unwrap());
+}
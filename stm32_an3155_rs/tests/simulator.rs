@@ -0,0 +1,1146 @@
+use std::time::Duration;
+use stm32_an3155_rs::{
+    crc32,
+    sim::{Fault, Simulator, DEFAULT_COMMANDS, ERASED_BYTE},
+    BankErase, BaudProbe, BootPins, BootloaderCommand, Builder, ControlLine, ControlSequence,
+    Device, EraseCommand, EraseStrategy, Error, FlashLayout, FlashOptions, Image, OptionBytes,
+    Phase, Progress, Response, RetryPolicy, Segment, SpecialResponse, StressCheck, Transport,
+    VerifyMode, AN3155,
+};
+
+const FLASH: u32 = 0x0800_0000;
+
+fn connect(sim: Simulator) -> AN3155<Simulator> {
+    Builder::with_transport(sim).initialize().unwrap()
+}
+
+fn extended_erase_sim() -> Simulator {
+    Simulator::new().and_version(0x31).and_commands(&[
+        BootloaderCommand::Get,
+        BootloaderCommand::GetVersion,
+        BootloaderCommand::GetId,
+        BootloaderCommand::ReadMemory,
+        BootloaderCommand::WriteMemory,
+        BootloaderCommand::ExtendedErase,
+    ])
+}
+
+#[test]
+fn initialize_synchronizes() {
+    let an3155 = connect(Simulator::new());
+    assert!(an3155.transport().is_synced());
+}
+
+#[test]
+fn device_information() {
+    let mut an3155 = connect(Simulator::new().and_pid(0x0413).and_version(0x31));
+
+    assert_eq!((3, 1), an3155.get_version().unwrap().value());
+    assert_eq!(0x0413, an3155.get_id().unwrap());
+    let commands = an3155.get_commands().unwrap();
+    assert_eq!(DEFAULT_COMMANDS, &commands[..]);
+    assert!(matches!(
+        an3155.get_erase_command().unwrap(),
+        EraseCommand::Erase
+    ));
+}
+
+#[test]
+fn write_and_read_memory() {
+    let mut an3155 = connect(Simulator::new());
+    let data: Vec<u8> = (0..=255).collect();
+
+    an3155.write_memory(FLASH + 0x400, &data).unwrap();
+    let mut buf = vec![0u8; data.len()];
+    an3155.read_memory(FLASH + 0x400, &mut buf).unwrap();
+
+    assert_eq!(data, buf);
+    assert_eq!(&data[..], &an3155.transport().flash()[0x400..0x500]);
+}
+
+#[test]
+fn flash_writes_only_clear_bits() {
+    let mut an3155 = connect(Simulator::new());
+
+    an3155.write_memory(FLASH, &[0x0F]).unwrap();
+    an3155.write_memory(FLASH, &[0xF0]).unwrap();
+    assert_eq!(0x00, an3155.transport().flash()[0]);
+}
+
+#[test]
+fn standard_erase_pages() {
+    let sim = Simulator::new().and_flash_contents(FLASH, &[0u8; 3 * 1024]);
+    let mut an3155 = connect(sim);
+
+    an3155.standard_erase(&[0, 2]).unwrap();
+    let flash = an3155.transport().flash();
+    assert!(flash[..1024].iter().all(|&b| b == ERASED_BYTE));
+    assert!(flash[1024..2048].iter().all(|&b| b == 0));
+    assert!(flash[2048..3072].iter().all(|&b| b == ERASED_BYTE));
+}
+
+#[test]
+fn standard_global_erase() {
+    let sim = Simulator::new().and_flash_contents(FLASH + 0x1_0000, &[0u8; 16]);
+    let mut an3155 = connect(sim);
+
+    an3155.standard_global_erase().unwrap();
+    assert!(an3155.transport().flash().iter().all(|&b| b == ERASED_BYTE));
+}
+
+#[test]
+fn extended_erase_pages() {
+    let sim = extended_erase_sim().and_flash_contents(FLASH, &[0u8; 2 * 1024]);
+    let mut an3155 = connect(sim);
+
+    assert!(matches!(
+        an3155.get_erase_command().unwrap(),
+        EraseCommand::ExtendedErase
+    ));
+    an3155.extended_erase(&[1]).unwrap();
+    let flash = an3155.transport().flash();
+    assert!(flash[..1024].iter().all(|&b| b == 0));
+    assert!(flash[1024..2048].iter().all(|&b| b == ERASED_BYTE));
+}
+
+#[test]
+fn extended_bank_erase() {
+    let sim = extended_erase_sim()
+        .and_flash_contents(FLASH, &[0u8; 16])
+        .and_flash_contents(FLASH + 0x1_0000, &[0u8; 16]);
+    let mut an3155 = connect(sim);
+
+    an3155.extended_global_erase(BankErase::Bank2).unwrap();
+    assert_eq!(0, an3155.transport().flash()[0]);
+    assert_eq!(ERASED_BYTE, an3155.transport().flash()[0x1_0000]);
+
+    an3155.extended_global_erase(BankErase::Global).unwrap();
+    assert_eq!(ERASED_BYTE, an3155.transport().flash()[0]);
+}
+
+#[test]
+fn unsupported_command_is_not_sent() {
+    // Any byte after Get and GetVersion would be dropped
+    let sim = Simulator::new().and_fault(5, Fault::Drop);
+    let mut an3155 = connect(sim);
+
+    let err = an3155.extended_erase(&[0]).unwrap_err();
+    assert!(matches!(
+        err,
+        Error::Unsupported(BootloaderCommand::ExtendedErase)
+    ));
+    assert!(!an3155.supports(BootloaderCommand::GetChecksum).unwrap());
+    let err = an3155.get_checksum(FLASH, 4).unwrap_err();
+    assert!(matches!(
+        err,
+        Error::Unsupported(BootloaderCommand::GetChecksum)
+    ));
+}
+
+#[test]
+fn unadvertised_command_is_nacked_by_simulator() {
+    let mut an3155 = connect(Simulator::new());
+    let opcode = BootloaderCommand::ExtendedErase as u8;
+
+    let transport = an3155.transport_mut();
+    transport.write(&[opcode, !opcode]).unwrap();
+    let mut response = [0u8];
+    transport.read_exact(&mut response).unwrap();
+    assert_eq!(Response::Nack as u8, response[0]);
+}
+
+#[test]
+fn invalid_address_is_nacked() {
+    let mut an3155 = connect(Simulator::new());
+
+    let mut buf = [0u8; 4];
+    let err = an3155.read_memory(0x4000_0000, &mut buf).unwrap_err();
+    assert!(matches!(err, Error::Nack(BootloaderCommand::ReadMemory)));
+}
+
+#[test]
+fn bad_checksum_is_nacked() {
+    let mut sim = Simulator::new();
+    sim.write(&[0x7F]).unwrap();
+    let mut ack = [0u8];
+    sim.read_exact(&mut ack).unwrap();
+
+    // WriteMemory with a corrupted address checksum
+    sim.write(&[0x31, 0xCE]).unwrap();
+    sim.write(&[0x08, 0x00, 0x00, 0x00, 0x00]).unwrap();
+    let mut response = [0u8; 2];
+    sim.read_exact(&mut response).unwrap();
+    assert_eq!([0x79, 0x1F], response);
+}
+
+#[test]
+fn read_protection_blocks_memory_access() {
+    let mut an3155 = connect(Simulator::new().and_read_protection(true));
+
+    let mut buf = [0u8; 4];
+    assert!(an3155.read_memory(FLASH, &mut buf).is_err());
+    assert!(an3155.write_memory(FLASH, &buf).is_err());
+    assert_eq!(0x0410, an3155.get_id().unwrap());
+}
+
+#[test]
+fn write_unprotect_resyncs_after_reset() {
+    let mut an3155 = Builder::with_transport(Simulator::new())
+        .and_reset_delay(Duration::ZERO)
+        .initialize()
+        .unwrap();
+
+    an3155.write_unprotect().unwrap();
+    assert!(an3155.transport().is_synced());
+    assert_eq!((2, 2), an3155.get_version().unwrap().value());
+}
+
+#[test]
+fn go_starts_application() {
+    let mut an3155 = connect(Simulator::new());
+
+    an3155.go(FLASH).unwrap();
+    assert_eq!(Some(FLASH), an3155.transport().application_address());
+    assert!(an3155.get_id().is_err());
+}
+
+#[test]
+fn go_rejects_unmapped_address() {
+    let mut an3155 = connect(Simulator::new());
+
+    assert!(an3155.go(0x4000_0000).is_err());
+    assert_eq!(None, an3155.transport().application_address());
+}
+
+#[test]
+fn readout_protect_and_unprotect() {
+    let sim = Simulator::new().and_flash_contents(FLASH, &[0x55; 16]);
+    let mut an3155 = Builder::with_transport(sim)
+        .and_reset_delay(Duration::ZERO)
+        .initialize()
+        .unwrap();
+
+    an3155.readout_protect().unwrap();
+    assert!(an3155.transport().read_protected());
+    assert!(an3155.transport().is_synced());
+    let mut buf = [0u8; 16];
+    assert!(an3155.read_memory(FLASH, &mut buf).is_err());
+
+    an3155.readout_unprotect().unwrap();
+    assert!(!an3155.transport().read_protected());
+    an3155.read_memory(FLASH, &mut buf).unwrap();
+    assert_eq!([ERASED_BYTE; 16], buf);
+}
+
+#[test]
+fn write_protect_sectors() {
+    let layout = FlashLayout::uniform(FLASH, 1024, 128);
+    let mut an3155 = Builder::with_transport(Simulator::new())
+        .and_reset_delay(Duration::ZERO)
+        .initialize()
+        .unwrap();
+
+    an3155.write_protect(&layout, &[0, 1]).unwrap();
+    assert_eq!(vec![0, 1], an3155.transport().write_protected_pages());
+    assert!(an3155.transport().is_synced());
+    assert!(an3155.write_memory(FLASH + 0x400, &[0]).is_err());
+    assert!(an3155.standard_erase(&[1]).is_err());
+    an3155.write_memory(FLASH + 0x800, &[0]).unwrap();
+}
+
+#[test]
+fn write_protect_all_256_sectors() {
+    let layout = FlashLayout::uniform(FLASH, 1024, 256);
+    let sim = Simulator::new().and_flash(FLASH, 1024, 256);
+    let mut an3155 = Builder::with_transport(sim)
+        .and_reset_delay(Duration::ZERO)
+        .initialize()
+        .unwrap();
+
+    let sectors: Vec<u8> = (0..=255).collect();
+    an3155.write_protect(&layout, &sectors).unwrap();
+    assert_eq!(
+        (0..256).collect::<Vec<_>>(),
+        an3155.transport().write_protected_pages()
+    );
+    assert!(an3155.transport().is_synced());
+}
+
+#[test]
+fn write_protect_validates_sectors() {
+    let layout = FlashLayout::uniform(FLASH, 1024, 16);
+    let mut an3155 = connect(Simulator::new());
+
+    let err = an3155.write_protect(&layout, &[3, 16]).unwrap_err();
+    assert!(matches!(
+        err,
+        Error::SectorOutOfRange {
+            sector: 16,
+            count: 16
+        }
+    ));
+    assert!(an3155.transport().write_protected_pages().is_empty());
+}
+
+#[test]
+fn write_protect_sectors_group_pages() {
+    let layout = FlashLayout::uniform(FLASH, 1024, 16).and_wrp_sectors(4, 3);
+    let sim = Simulator::new()
+        .and_flash(FLASH, 1024, 16)
+        .and_wrp_sectors(4, 3);
+    let mut an3155 = Builder::with_transport(sim)
+        .and_reset_delay(Duration::ZERO)
+        .initialize()
+        .unwrap();
+
+    let err = an3155.write_protect(&layout, &[3]).unwrap_err();
+    assert!(matches!(
+        err,
+        Error::SectorOutOfRange {
+            sector: 3,
+            count: 3
+        }
+    ));
+
+    an3155.write_protect(&layout, &[0, 2]).unwrap();
+    assert_eq!(
+        vec![0, 1, 2, 3, 8, 9, 10, 11, 12, 13, 14, 15],
+        an3155.transport().write_protected_pages()
+    );
+}
+
+#[test]
+fn simulator_nacks_out_of_range_wrp_sector() {
+    let sim = Simulator::new()
+        .and_flash(FLASH, 1024, 16)
+        .and_wrp_sectors(4, 4);
+    let mut an3155 = connect(sim);
+
+    // The per-page layout lets sector 4 through, the bootloader rejects it
+    let layout = FlashLayout::uniform(FLASH, 1024, 16);
+    let err = an3155.write_protect(&layout, &[4]).unwrap_err();
+    assert!(matches!(
+        err.root(),
+        Error::Nack(BootloaderCommand::WriteProtect)
+    ));
+    assert!(an3155.transport().write_protected_pages().is_empty());
+}
+
+#[test]
+fn get_checksum_matches_host_crc() {
+    let commands = [DEFAULT_COMMANDS, &[BootloaderCommand::GetChecksum]].concat();
+    let data: Vec<u8> = (0..64).collect();
+    let sim = Simulator::new()
+        .and_commands(&commands)
+        .and_flash_contents(FLASH + 0x100, &data);
+    let mut an3155 = connect(sim);
+
+    assert_eq!(
+        crc32(&data),
+        an3155.get_checksum(FLASH + 0x100, 64).unwrap()
+    );
+    let err = an3155.get_checksum(FLASH, 6).unwrap_err();
+    assert!(matches!(err, Error::ChecksumSize(6)));
+}
+
+#[test]
+fn special_commands() {
+    let commands = [
+        DEFAULT_COMMANDS,
+        &[
+            BootloaderCommand::Special,
+            BootloaderCommand::ExtendedSpecial,
+        ],
+    ]
+    .concat();
+    let response = SpecialResponse {
+        data: vec![0xDE, 0xAD],
+        status: vec![0x00],
+    };
+    let sim = Simulator::new()
+        .and_commands(&commands)
+        .and_special(0x0102, response.clone());
+    let mut an3155 = connect(sim);
+
+    assert_eq!(response, an3155.special(0x0102, &[1, 2, 3]).unwrap());
+    assert_eq!(
+        vec![0x00],
+        an3155.extended_special(0x0102, &[4], &[5; 1024]).unwrap()
+    );
+    assert!(an3155.special(0x0BAD, &[]).is_err());
+    assert!(an3155.special(0x0102, &[0; 129]).is_err());
+
+    let requests = an3155.transport().special_requests();
+    assert_eq!(2, requests.len());
+    assert_eq!(vec![1, 2, 3], requests[0].data);
+    assert_eq!(None, requests[0].file_data);
+    assert_eq!(vec![4], requests[1].data);
+    assert_eq!(Some(vec![5; 1024]), requests[1].file_data);
+}
+
+#[test]
+fn get_device() {
+    let mut an3155 = connect(Simulator::new().and_pid(0x0410));
+    let device = an3155.get_device().unwrap().unwrap();
+
+    assert_eq!(128 * 1024, device.flash.size());
+    assert_eq!(
+        an3155.transport().flash().len(),
+        device.flash.size() as usize
+    );
+}
+
+#[test]
+fn errors_keep_context_and_nacked_command() {
+    let mut an3155 = connect(Simulator::new());
+
+    let err = an3155.go(0x4000_0000).unwrap_err();
+    assert_eq!("Bootloader rejected the Go address", err.to_string());
+    assert!(matches!(err.root(), Error::Nack(BootloaderCommand::Go)));
+}
+
+#[test]
+fn timeout_is_an_io_error() {
+    let mut an3155 = Builder::with_transport(Simulator::new())
+        .skip_initialization()
+        .unwrap();
+
+    // The simulator does not answer until it is synced
+    let err = an3155.get_id().unwrap_err();
+    assert!(matches!(
+        err.root(),
+        Error::Io(e) if e.kind() == std::io::ErrorKind::TimedOut
+    ));
+}
+
+/// Index of the byte count frame of the first write or read after syncing,
+/// which follows the sync byte, the Get and GetVersion queries for the
+/// command list, the command and the address
+const FIRST_FRAME_BYTE: usize = 1 + 4 + 2 + 5;
+
+#[test]
+fn corrupted_write_is_retried() {
+    let sim = || Simulator::new().and_fault(FIRST_FRAME_BYTE + 1, Fault::Corrupt);
+
+    let mut an3155 = connect(sim());
+    let err = an3155.write_memory(FLASH, &[1, 2, 3, 4]).unwrap_err();
+    assert!(matches!(err, Error::Nack(BootloaderCommand::WriteMemory)));
+
+    let mut an3155 = Builder::with_transport(sim())
+        .and_retry_policy(RetryPolicy::new(3).and_backoff(Duration::ZERO, 1))
+        .initialize()
+        .unwrap();
+    an3155.write_memory(FLASH, &[1, 2, 3, 4]).unwrap();
+    assert_eq!(&[1, 2, 3, 4], &an3155.transport().flash()[..4]);
+}
+
+#[test]
+fn dropped_byte_is_retried_after_resync() {
+    let sim = Simulator::new()
+        .and_flash_contents(FLASH, &[9, 8, 7, 6])
+        .and_fault(FIRST_FRAME_BYTE + 1, Fault::Drop);
+    let mut an3155 = Builder::with_transport(sim)
+        .and_retry_policy(RetryPolicy::new(2).and_backoff(Duration::ZERO, 1))
+        .initialize()
+        .unwrap();
+
+    let mut buf = [0u8; 4];
+    an3155.read_memory(FLASH, &mut buf).unwrap();
+    assert_eq!([9, 8, 7, 6], buf);
+    // Still in step for the next command
+    assert_eq!(0x0410, an3155.get_id().unwrap());
+}
+
+#[test]
+fn invalid_arguments_are_not_retried() {
+    let mut an3155 = Builder::with_transport(Simulator::new())
+        .and_retry_policy(RetryPolicy::new(5))
+        .initialize()
+        .unwrap();
+
+    let err = an3155.write_memory(FLASH, &[0; 257]).unwrap_err();
+    assert!(matches!(err, Error::WriteBytesCount(257)));
+    assert!(!stm32_an3155_rs::is_retryable(&err));
+}
+
+#[test]
+fn erase_pages_uses_supported_command() {
+    let sim = Simulator::new().and_flash_contents(FLASH, &[0u8; 3 * 1024]);
+    let mut an3155 = connect(sim);
+
+    an3155.erase_pages(&[0, 2], &mut ()).unwrap();
+    let flash = an3155.transport().flash();
+    assert!(flash[..1024].iter().all(|&b| b == ERASED_BYTE));
+    assert!(flash[1024..2048].iter().all(|&b| b == 0));
+
+    let err = an3155.erase_pages(&[256], &mut ()).unwrap_err();
+    assert!(matches!(
+        err,
+        Error::PageOutOfRange {
+            page: 256,
+            max: 255
+        }
+    ));
+
+    let mut an3155 = connect(extended_erase_sim().and_flash_contents(FLASH, &[0u8; 1024]));
+    an3155.erase_pages(&[0], &mut ()).unwrap();
+    assert_eq!(ERASED_BYTE, an3155.transport().flash()[0]);
+}
+
+#[test]
+fn write_and_verify_image_report_progress() {
+    let image = Image {
+        segments: vec![
+            Segment {
+                address: FLASH,
+                data: vec![0x11; 300],
+            },
+            Segment {
+                address: FLASH + 0x1000,
+                data: vec![0x22; 100],
+            },
+        ],
+        entry: None,
+    };
+    let mut an3155 = connect(Simulator::new());
+
+    let mut updates = Vec::new();
+    let mut observer = |p: &Progress| updates.push((p.phase, p.done, p.total));
+    an3155.write_image(&image, &mut observer).unwrap();
+    let mismatches = an3155.verify_image(&image, &mut observer).unwrap();
+    assert!(mismatches.is_empty());
+
+    let expected = [0, 256, 300, 400].map(|done| (Phase::Write, done, 400));
+    assert_eq!(&expected[..], &updates[..4]);
+    let expected = [0, 256, 300, 400].map(|done| (Phase::Verify, done, 400));
+    assert_eq!(&expected[..], &updates[4..]);
+}
+
+#[test]
+fn verify_image_reports_mismatched_runs() {
+    let mut contents = vec![0x11; 600];
+    contents[10] = 0x00;
+    contents[255..258].fill(0x00);
+    let sim = Simulator::new().and_flash_contents(FLASH, &contents);
+    let mut an3155 = connect(sim);
+
+    let image = Image::from_binary(FLASH, vec![0x11; 600]);
+    let mismatches = an3155.verify_image(&image, &mut ()).unwrap();
+    assert_eq!(2, mismatches.len());
+    assert_eq!(FLASH + 10, mismatches[0].address);
+    assert_eq!(vec![0x11], mismatches[0].expected);
+    assert_eq!(FLASH + 255, mismatches[1].address);
+    assert_eq!(vec![0x00; 3], mismatches[1].actual);
+}
+
+#[test]
+fn read_region_rejects_addresses_past_4_gib() {
+    let mut an3155 = connect(Simulator::new());
+
+    let err = an3155
+        .read_region(0xFFFF_FF00, &mut [0u8; 512], &mut ())
+        .unwrap_err();
+    assert!(matches!(err, Error::AddressOutOfRange { len: 512, .. }));
+}
+
+#[test]
+fn flash_image_erases_writes_and_verifies() {
+    let sim = Simulator::new().and_flash_contents(FLASH + 1024, &[0u8; 16]);
+    let mut an3155 = connect(sim);
+
+    let image = Image::from_binary(FLASH + 1024, vec![0x5A; 600]);
+    let mut phases = Vec::new();
+    let mut observer = |p: &Progress| phases.push(p.phase);
+    an3155
+        .flash_image(&image, &FlashOptions::default(), &mut observer)
+        .unwrap();
+    assert_eq!(&[0x5A; 600][..], &an3155.transport().flash()[1024..1624]);
+    phases.dedup();
+    assert_eq!(vec![Phase::Erase, Phase::Write, Phase::Verify], phases);
+    assert_eq!(None, an3155.transport().application_address());
+}
+
+#[test]
+fn flash_image_writes_ram_segments_without_erasing() {
+    let sim = Simulator::new().and_flash_contents(FLASH, &[0u8; 16]);
+    let mut an3155 = connect(sim);
+
+    let blocks = [
+        Segment {
+            address: FLASH,
+            data: vec![0x11; 8],
+        },
+        Segment {
+            address: 0x2000_1000,
+            data: vec![0x22; 8],
+        },
+    ];
+    let image = Image::from_blocks(blocks, None).unwrap();
+    an3155
+        .flash_image(&image, &FlashOptions::default(), &mut ())
+        .unwrap();
+    assert_eq!([0x11; 8], an3155.transport().flash()[..8]);
+    assert_eq!([0x22; 8], an3155.transport().ram()[0x1000..0x1008]);
+}
+
+#[test]
+fn flash_image_rejects_segments_outside_flash_and_ram_before_erasing() {
+    let sim = Simulator::new().and_flash_contents(FLASH, &[0u8; 16]);
+    let mut an3155 = connect(sim);
+
+    let blocks = [
+        Segment {
+            address: FLASH,
+            data: vec![0x11; 8],
+        },
+        Segment {
+            address: 0x4000_0000,
+            data: vec![0x22; 8],
+        },
+    ];
+    let image = Image::from_blocks(blocks, None).unwrap();
+    let err = an3155
+        .flash_image(&image, &FlashOptions::default(), &mut ())
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        Error::AddressOutOfRange {
+            address: 0x4000_0000,
+            len: 8
+        }
+    ));
+    assert_eq!([0u8; 16], an3155.transport().flash()[..16]);
+}
+
+#[test]
+fn flash_image_reports_verify_mismatch() {
+    let commands = [DEFAULT_COMMANDS, &[BootloaderCommand::GetChecksum]].concat();
+    let sim = Simulator::new()
+        .and_commands(&commands)
+        .and_flash_contents(FLASH, &[0x00; 4]);
+    let mut an3155 = connect(sim);
+
+    // Without erasing, flash keeps the cleared bits
+    let image = Image::from_binary(FLASH, vec![0x11; 4]);
+    for verify in [VerifyMode::Crc, VerifyMode::ReadBack] {
+        let options = FlashOptions::default()
+            .and_erase(EraseStrategy::Skip)
+            .and_verify(verify);
+        let err = an3155.flash_image(&image, &options, &mut ()).unwrap_err();
+        assert!(matches!(
+            err.root(),
+            Error::VerifyMismatch { address: FLASH }
+        ));
+    }
+}
+
+#[test]
+fn flash_image_checksums_unaligned_segments() {
+    let commands = [DEFAULT_COMMANDS, &[BootloaderCommand::GetChecksum]].concat();
+    let sim = Simulator::new().and_commands(&commands);
+    let mut an3155 = connect(sim);
+
+    // Partial words around the segments hold data the image does not cover
+    let blocks = [
+        Segment {
+            address: FLASH,
+            data: vec![0x11; 2],
+        },
+        Segment {
+            address: FLASH + 3,
+            data: vec![0x22],
+        },
+        Segment {
+            address: FLASH + 5,
+            data: vec![0x33; 9],
+        },
+        Segment {
+            address: 0x2000_1001,
+            data: vec![0x44; 3],
+        },
+    ];
+    let image = Image::from_blocks(blocks, None).unwrap();
+    let options = FlashOptions::default().and_verify(VerifyMode::Crc);
+    an3155.flash_image(&image, &options, &mut ()).unwrap();
+    assert_eq!(
+        [0x00, 0x44, 0x44, 0x44],
+        an3155.transport().ram()[0x1000..0x1004]
+    );
+
+    // Bytes outside the word-aligned interior are still verified
+    let image = Image::from_binary(FLASH + 5, [&[0x33; 8][..], &[0x55]].concat());
+    let err = an3155.verify_image_checksum(&image, &mut ()).unwrap_err();
+    assert!(matches!(
+        err.root(),
+        Error::VerifyMismatch { address } if *address == FLASH + 13
+    ));
+}
+
+#[test]
+fn flash_image_needs_layout_of_unknown_device() {
+    let mut an3155 = connect(Simulator::new().and_pid(0x0999));
+
+    let image = Image::from_binary(FLASH, vec![0x11; 4]);
+    let err = an3155
+        .flash_image(&image, &FlashOptions::default(), &mut ())
+        .unwrap_err();
+    assert!(matches!(err, Error::UnknownDevice(0x0999)));
+
+    let options = FlashOptions::default()
+        .and_layout(FlashLayout::uniform(FLASH, 1024, 64))
+        .and_go(true);
+    an3155.flash_image(&image, &options, &mut ()).unwrap();
+    assert_eq!(Some(FLASH), an3155.transport().application_address());
+}
+
+#[test]
+fn image_segments_past_end_of_address_space_are_rejected() {
+    let mut an3155 = connect(Simulator::new().and_pid(0x0999));
+
+    let image = Image::from_binary(0xFFFF_FF00, vec![0x11; 0x200]);
+    let options = FlashOptions::default().and_erase(EraseStrategy::Skip);
+    let err = an3155.flash_image(&image, &options, &mut ()).unwrap_err();
+    let out_of_range = |err: &Error| {
+        matches!(
+            err,
+            Error::AddressOutOfRange {
+                address: 0xFFFF_FF00,
+                len: 0x200
+            }
+        )
+    };
+    assert!(out_of_range(&err));
+    assert!(out_of_range(
+        &an3155.write_image(&image, &mut ()).unwrap_err()
+    ));
+    assert!(out_of_range(
+        &an3155.verify_image(&image, &mut ()).unwrap_err()
+    ));
+    assert!(out_of_range(
+        &an3155.verify_image_checksum(&image, &mut ()).unwrap_err()
+    ));
+}
+
+#[test]
+fn boot_pins_reset_into_bootloader_and_application() {
+    let pins = BootPins::default()
+        .and_lines(ControlLine::Rts, ControlLine::Dtr)
+        .and_inverted(true, false)
+        .and_timing(Duration::ZERO, Duration::ZERO);
+    let sim = Simulator::new().and_boot_pins(pins.clone());
+    assert!(Builder::with_transport(sim).initialize().is_err());
+
+    let sim = Simulator::new().and_boot_pins(pins.clone());
+    let mut an3155 = Builder::with_transport(sim)
+        .and_boot_pins(pins)
+        .initialize()
+        .unwrap();
+    assert!(an3155.transport().is_synced());
+    // Reset released by asserting the inverted line, BOOT0 selected
+    assert!(an3155.transport().control_line(ControlLine::Rts));
+    assert!(an3155.transport().control_line(ControlLine::Dtr));
+
+    an3155.exit_bootloader().unwrap();
+    assert_eq!(Some(FLASH), an3155.transport().application_address());
+    assert!(!an3155.transport().control_line(ControlLine::Dtr));
+}
+
+/// Transport implementing only what the trait requires
+struct MinimalLink(Simulator);
+
+impl Transport for MinimalLink {
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.0.read_exact(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.0.set_timeout(timeout)
+    }
+
+    fn set_baud(&mut self, baud_rate: u32) -> std::io::Result<()> {
+        self.0.set_baud(baud_rate)
+    }
+}
+
+#[test]
+fn transport_without_control_lines() {
+    let an3155 = Builder::with_transport(MinimalLink(Simulator::new()))
+        .initialize()
+        .unwrap();
+    assert!(an3155.transport().0.is_synced());
+
+    let entry = ControlSequence::new().and_set(ControlLine::Dtr, true);
+    let result = Builder::with_transport(MinimalLink(Simulator::new()))
+        .and_entry_sequence(entry)
+        .initialize();
+    let err = result.err().unwrap();
+    assert!(matches!(
+        err.root(),
+        Error::Io(e) if e.kind() == std::io::ErrorKind::Unsupported
+    ));
+}
+
+/// Transport keeping a copy of everything written to the simulator
+struct RecordingLink {
+    sim: Simulator,
+    written: Vec<u8>,
+}
+
+impl Transport for RecordingLink {
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.sim.read_exact(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.written.extend_from_slice(buf);
+        self.sim.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.sim.flush()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.sim.set_timeout(timeout)
+    }
+
+    fn set_baud(&mut self, baud_rate: u32) -> std::io::Result<()> {
+        self.sim.set_baud(baud_rate)
+    }
+
+    fn set_control_line(&mut self, line: ControlLine, level: bool) -> std::io::Result<()> {
+        self.sim.set_control_line(line, level)
+    }
+}
+
+#[test]
+fn bootloader_info_is_queried_again_after_reset() {
+    let link = RecordingLink {
+        sim: Simulator::new(),
+        written: Vec::new(),
+    };
+    let mut an3155 = Builder::with_transport(link)
+        .and_reset_delay(Duration::ZERO)
+        .initialize()
+        .unwrap();
+    let get = [
+        BootloaderCommand::Get as u8,
+        !(BootloaderCommand::Get as u8),
+    ];
+    let gets = |an3155: &AN3155<RecordingLink>| {
+        an3155
+            .transport()
+            .written
+            .windows(2)
+            .filter(|w| *w == get)
+            .count()
+    };
+
+    an3155.get_info().unwrap();
+    an3155.get_info().unwrap();
+    assert_eq!(1, gets(&an3155));
+
+    an3155.readout_protect().unwrap();
+    assert!(an3155.transport().sim.read_protected());
+    an3155.get_info().unwrap();
+    assert_eq!(2, gets(&an3155));
+}
+
+#[test]
+fn custom_entry_sequence_runs_before_sync() {
+    let entry = ControlSequence::new()
+        .and_set(ControlLine::Dtr, true)
+        .and_delay(Duration::from_millis(1))
+        .and_set(ControlLine::Rts, true);
+    let an3155 = Builder::with_transport(Simulator::new())
+        .and_entry_sequence(entry)
+        .initialize()
+        .unwrap();
+    assert!(an3155.transport().control_line(ControlLine::Dtr));
+    assert!(an3155.transport().control_line(ControlLine::Rts));
+
+    // Skipping initialization leaves the control lines alone
+    let an3155 = Builder::with_transport(Simulator::new())
+        .and_entry_sequence(ControlSequence::new().and_set(ControlLine::Dtr, true))
+        .skip_initialization()
+        .unwrap();
+    assert!(!an3155.transport().control_line(ControlLine::Dtr));
+}
+
+#[test]
+fn initialize_accepts_already_synced_bootloader() {
+    let mut an3155 = connect(Simulator::new());
+    // Leave an unread response behind, as an interrupted session would
+    let get = BootloaderCommand::Get as u8;
+    an3155.transport_mut().write(&[get, !get]).unwrap();
+
+    let mut an3155 = Builder::with_transport(an3155.into_transport())
+        .and_sync_attempts(2, Duration::ZERO)
+        .initialize()
+        .unwrap();
+    assert_eq!(0x0410, an3155.get_id().unwrap());
+}
+
+#[test]
+fn sync_attempts_are_enough_to_detect_synced_bootloader() {
+    let an3155 = connect(Simulator::new());
+    let an3155 = Builder::with_transport(an3155.into_transport())
+        .and_sync_attempts(1, Duration::ZERO)
+        .initialize()
+        .unwrap();
+    assert!(an3155.transport().is_synced());
+}
+
+#[test]
+fn initialize_retries_sync() {
+    let sim = Simulator::new().and_fault(0, Fault::Corrupt);
+    let an3155 = Builder::with_transport(sim)
+        .and_sync_attempts(2, Duration::ZERO)
+        .initialize()
+        .unwrap();
+    assert!(an3155.transport().is_synced());
+
+    let sim = Simulator::new()
+        .and_fault(0, Fault::Corrupt)
+        .and_fault(1, Fault::Drop);
+    let err = Builder::with_transport(sim)
+        .and_sync_attempts(2, Duration::ZERO)
+        .initialize()
+        .err()
+        .unwrap();
+    assert!(matches!(err, Error::NoBootloader { attempts: 2 }));
+}
+
+#[test]
+fn baud_probe_finds_fastest_reliable_rate() {
+    let pins = BootPins::default().and_timing(Duration::ZERO, Duration::ZERO);
+    let sim = Simulator::new()
+        .and_boot_pins(pins.clone())
+        .and_max_baud_rate(57_600);
+    let probe = BaudProbe::new(&[9_600, 115_200, 57_600]).and_check(Some(StressCheck::Read {
+        address: FLASH,
+        len: 300,
+    }));
+    let an3155 = Builder::with_transport(sim)
+        .and_boot_pins(pins)
+        .and_sync_attempts(1, Duration::ZERO)
+        .and_baud_probe(probe)
+        .initialize()
+        .unwrap();
+    assert_eq!(Some(57_600), an3155.baud_rate());
+    assert_eq!(Some(57_600), an3155.transport().baud_rate());
+
+    let sim = Simulator::new().and_max_baud_rate(57_600);
+    let err = Builder::with_transport(sim)
+        .and_sync_attempts(1, Duration::ZERO)
+        .and_baud_probe(BaudProbe::new(&[115_200, 230_400]))
+        .initialize()
+        .err()
+        .unwrap();
+    assert!(matches!(err, Error::NoBaudRate { rates } if rates == [230_400, 115_200]));
+}
+
+#[test]
+fn baud_probe_queries_bootloader_info_after_each_reset() {
+    let pins = BootPins::default().and_timing(Duration::ZERO, Duration::ZERO);
+    // Sync, GetVersion and the info query are bytes 0 to 6, so the read check fails at 115200
+    let link = RecordingLink {
+        sim: Simulator::new()
+            .and_boot_pins(pins.clone())
+            .and_fault(7, Fault::Corrupt),
+        written: Vec::new(),
+    };
+    let probe = BaudProbe::new(&[57_600, 115_200]).and_check(Some(StressCheck::Read {
+        address: FLASH,
+        len: 16,
+    }));
+    let an3155 = Builder::with_transport(link)
+        .and_boot_pins(pins)
+        .and_sync_attempts(2, Duration::ZERO)
+        .and_baud_probe(probe)
+        .initialize()
+        .unwrap();
+    assert_eq!(Some(57_600), an3155.baud_rate());
+
+    let get = [
+        BootloaderCommand::Get as u8,
+        !(BootloaderCommand::Get as u8),
+    ];
+    let written = &an3155.transport().written;
+    assert_eq!(2, written.windows(2).filter(|w| *w == get).count());
+}
+
+#[test]
+fn default_baud_probe_writes_to_ram_available_to_host() {
+    // STM32F10xxx medium-density RAM for the host starts at 0x2000_0200
+    let sim = Simulator::new().and_ram(0x2000_0000, 0x800);
+    let an3155 = Builder::with_transport(sim)
+        .and_baud_probe(BaudProbe::new(&[115_200]))
+        .initialize()
+        .unwrap();
+    let ram = an3155.transport().ram();
+    assert_eq!([0u8; 0x200], ram[..0x200]);
+    assert_eq!([0xA5, 0xA4, 0xA7, 0xA6], ram[0x200..0x204]);
+    assert_eq!(0xA5 ^ 0xFF, ram[0x5FF]);
+    assert_eq!(0, ram[0x600]);
+
+    // Unknown devices get a read check, leaving RAM alone
+    let sim = Simulator::new()
+        .and_pid(0x0999)
+        .and_flash(FLASH, 1024, 4)
+        .and_ram(0x2000_0000, 0x800);
+    let an3155 = Builder::with_transport(sim)
+        .and_baud_probe(BaudProbe::new(&[115_200]))
+        .initialize()
+        .unwrap();
+    assert!(an3155.transport().ram().iter().all(|&b| b == 0));
+}
+
+#[test]
+fn bootloader_info_is_cached() {
+    // Sync, Get and GetVersion are bytes 0 to 4, so the next command starts at 5
+    let sim = Simulator::new()
+        .and_unknown_commands(&[0xB2, 0xC3])
+        .and_fault(5, Fault::Drop);
+    let mut an3155 = connect(sim);
+
+    let info = an3155.get_info().unwrap().clone();
+    assert_eq!(vec![0xB2, 0xC3], info.unknown_opcodes());
+    assert_eq!(DEFAULT_COMMANDS, &info.commands()[..]);
+    assert!(info.supports(BootloaderCommand::Erase));
+    assert!(!info.supports(BootloaderCommand::GetChecksum));
+
+    // Neither of these queries the chip again
+    assert_eq!(info, *an3155.get_info().unwrap());
+    assert_eq!(DEFAULT_COMMANDS, &an3155.get_commands().unwrap()[..]);
+    assert!(an3155.get_id().unwrap_err().is_timeout());
+}
+
+#[test]
+fn option_bytes_are_decoded() {
+    let mut an3155 = connect(Simulator::new());
+
+    let option_bytes = an3155.read_option_bytes().unwrap();
+    assert_eq!("STM32F1", option_bytes.layout.family);
+    assert_eq!(0x1FFF_F800, option_bytes.address);
+    assert_eq!(Some(0xA5), option_bytes.get("RDP"));
+    assert_eq!(Some(1), option_bytes.get("nrst_stop"));
+    assert_eq!(None, option_bytes.get("BOR_LEV"));
+    assert!(option_bytes.complement_errors().is_empty());
+}
+
+#[test]
+fn f0_option_bytes_select_boot0_source() {
+    // STM32F07x, booting from the nBOOT0 bit rather than the BOOT0 pin
+    let mut bytes = [0xFF, 0x00].repeat(8);
+    bytes[..4].copy_from_slice(&[0xAA, 0x55, 0x77, 0x88]);
+    let sim = Simulator::new()
+        .and_pid(0x0448)
+        .and_option_bytes(0x1FFF_F800, &bytes);
+    let mut an3155 = connect(sim);
+
+    let option_bytes = an3155.read_option_bytes().unwrap();
+    assert_eq!("STM32F0", option_bytes.layout.family);
+    assert_eq!(Some(0), option_bytes.get("nBOOT0"));
+    assert_eq!(Some(1), option_bytes.get("nBOOT1"));
+    assert_eq!(Some(0), option_bytes.get("BOOT_SEL"));
+    assert_eq!(None, option_bytes.get("nBOOT_SEL"));
+}
+
+#[test]
+fn option_bytes_report_complement_errors() {
+    let mut bytes = [0xFF, 0x00].repeat(8);
+    bytes[5] = 0xFF;
+    let sim = Simulator::new().and_option_bytes(0x1FFF_F800, &bytes);
+    let mut an3155 = connect(sim);
+
+    let option_bytes = an3155.read_option_bytes().unwrap();
+    assert_eq!(vec![0x1FFF_F804], option_bytes.complement_errors());
+}
+
+#[test]
+fn option_bytes_are_written_with_complements() {
+    let mut an3155 = Builder::with_transport(Simulator::new())
+        .and_reset_delay(Duration::ZERO)
+        .initialize()
+        .unwrap();
+
+    let mut option_bytes = an3155.read_option_bytes().unwrap();
+    option_bytes.set("WDG_SW", 0).unwrap();
+    option_bytes.set("DATA1", 0x42).unwrap();
+    an3155.write_option_bytes(&option_bytes).unwrap();
+
+    assert!(an3155.transport().is_synced());
+    assert_eq!(
+        [0xA5, 0x5A, 0xFE, 0x01, 0xFF, 0x00, 0x42, 0xBD],
+        an3155.transport().option_bytes()[..8]
+    );
+    assert_eq!(option_bytes, an3155.read_option_bytes().unwrap());
+}
+
+#[test]
+fn option_bytes_of_wide_layouts_round_trip() {
+    let bytes = [0xAA, 0xF8, 0xFF, 0xDE, 0x55, 0x07, 0x00, 0x21];
+    let sim = Simulator::new()
+        .and_pid(0x0460)
+        .and_option_bytes(0x1FFF_7800, &bytes);
+    let mut an3155 = connect(sim);
+
+    let mut option_bytes = an3155.read_option_bytes().unwrap();
+    assert_eq!(vec![0xDEFF_F8AA], option_bytes.values);
+    assert_eq!(Some(0x3), option_bytes.get("NRST_MODE"));
+    assert_eq!(bytes.to_vec(), option_bytes.encode());
+
+    option_bytes.set("NRST_MODE", 1).unwrap();
+    assert_eq!(
+        vec![0xAA, 0xF8, 0xFF, 0xCE, 0x55, 0x07, 0x00, 0x31],
+        option_bytes.encode()
+    );
+}
+
+#[test]
+fn option_field_changes_are_validated() {
+    let mut an3155 = connect(Simulator::new());
+    let mut option_bytes = an3155.read_option_bytes().unwrap();
+    let original = option_bytes.clone();
+
+    let error = option_bytes.set("BOGUS", 0).unwrap_err();
+    assert!(matches!(error, Error::UnknownOptionField(ref name) if name == "BOGUS"));
+    let error = option_bytes.set("WDG_SW", 2).unwrap_err();
+    assert!(matches!(
+        error,
+        Error::OptionFieldValue {
+            name: "WDG_SW",
+            value: 2,
+            max: 1
+        }
+    ));
+    let error = option_bytes.set("RDP", 0xCC).unwrap_err();
+    assert!(matches!(error, Error::PermanentOptionValue { .. }));
+    assert_eq!(original, option_bytes);
+}
+
+#[test]
+fn option_bytes_need_known_layout() {
+    let mut an3155 = connect(Simulator::new().and_pid(0x0449));
+    let error = an3155.read_option_bytes().unwrap_err();
+    assert!(matches!(error, Error::UnknownOptionBytes(0x0449)));
+
+    let mut an3155 = connect(Simulator::new().and_pid(0xFFFF));
+    let error = an3155.read_option_bytes().unwrap_err();
+    assert!(matches!(error, Error::UnknownDevice(0xFFFF)));
+}
+
+#[test]
+fn option_bytes_decode_checks_length() {
+    let layout = Device::from_pid(0x0410).unwrap().option_layout.unwrap();
+    assert!(OptionBytes::decode(layout, 0x1FFF_F800, &[0; 4]).is_err());
+}